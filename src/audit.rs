@@ -0,0 +1,100 @@
+use std::path::PathBuf;
+use zt::controller::{AuditSink, AuditRecord, FileAuditSink};
+use failure::Fallible;
+
+/// Audit sink writing JSON lines to a file that is rotated once it grows
+/// past a maximum size
+///
+/// Rotated files get a numbered suffix, `<path>.1` being the most recent one,
+/// and at most `keep` of them are kept around.
+pub struct RotatingAuditSink {
+    path: PathBuf,
+    max_size: u64,
+    keep: usize,
+    sink: FileAuditSink,
+}
+
+impl RotatingAuditSink {
+    pub fn open(path: &str, max_size: u64, keep: usize) -> Fallible<Self> {
+        Ok(Self {
+            path: PathBuf::from(path),
+            max_size: max_size,
+            keep: keep,
+            sink: FileAuditSink::open(path)?,
+        })
+    }
+
+    fn rotated_path(&self, n: usize) -> PathBuf {
+        PathBuf::from(format!("{}.{}", self.path.display(), n))
+    }
+
+    fn rotate(&mut self) -> Fallible<()> {
+        if self.keep == 0 {
+            std::fs::remove_file(&self.path)?;
+        } else {
+            // Shift every rotated file one up, the oldest one gets overwritten
+            for n in (1..self.keep).rev() {
+                let from = self.rotated_path(n);
+                if from.exists() {
+                    std::fs::rename(&from, self.rotated_path(n + 1))?;
+                }
+            }
+            std::fs::rename(&self.path, self.rotated_path(1))?;
+        }
+
+        self.sink = FileAuditSink::open(&self.path)?;
+        Ok(())
+    }
+}
+
+impl AuditSink for RotatingAuditSink {
+    fn record(&mut self, record: &AuditRecord) -> Fallible<()> {
+        if self.sink.len()? >= self.max_size {
+            self.rotate()?;
+        }
+        self.sink.record(record)
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use zt::controller::Decision;
+
+    fn record(timestamp: i64) -> AuditRecord {
+        AuditRecord {
+            timestamp: timestamp,
+            nwid: 0xba7a59abb06f066b,
+            member: 0x99e5a948c2,
+            source: None,
+            decision: Decision::NotFound,
+            revision: None,
+            assigned_ips: vec![],
+        }
+    }
+
+    #[test]
+    fn test_rotating_audit_sink() -> Fallible<()> {
+        let dir = std::env::temp_dir().join(format!("rztc-audit-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir)?;
+        let path = dir.join("audit.log");
+        let path = path.to_str().unwrap();
+
+        // Every record is larger than max size so each write rotates
+        let mut sink = RotatingAuditSink::open(path, 10, 2)?;
+        for ts in 0..4 {
+            sink.record(&record(ts))?;
+        }
+
+        let read = |p: String| std::fs::read_to_string(p).unwrap();
+        assert!(read(path.to_string()).contains(r#""timestamp":3,"#));
+        assert!(read(format!("{}.1", path)).contains(r#""timestamp":2,"#));
+        assert!(read(format!("{}.2", path)).contains(r#""timestamp":1,"#));
+        assert!(!dir.join("audit.log.3").exists());
+
+        std::fs::remove_dir_all(&dir)?;
+
+        Ok(())
+    }
+}
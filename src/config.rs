@@ -1,7 +1,8 @@
 use serde::{Serialize, Deserialize};
 use std::str::FromStr;
 use std::collections::BTreeMap;
-use ipnetwork::Ipv4Network;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use ipnetwork::{IpNetwork, Ipv4Network};
 use failure::Fallible;
 use sha2::Digest;
 use zt::controller::rule::{Rule as ZTRule};
@@ -12,12 +13,196 @@ pub struct Config {
     pub port: u16,
     #[serde(default = "default_secondary_port")]
     pub secondary_port: u16,
+    // More ports to listen on
+    #[serde(default)]
+    pub extra_ports: Vec<u16>,
+    // Also listen on a random high port, like zerotier-one's third port
+    #[serde(default)]
+    pub random_port: bool,
+    // Local addresses every port is bound on
+    #[serde(default = "default_bind")]
+    pub bind: Vec<IpAddr>,
     pub identity_path: String,
+    pub state_path: Option<String>,
+    pub store_path: Option<String>,
+    #[serde(default)]
+    pub request_limits: RequestLimits,
+    pub audit: Option<Audit>,
+    // Unix socket used by commands like `rztc peers` to query the daemon
+    pub control_path: Option<String>,
+    // Network ids (16 hex characters) to join as a member
+    #[serde(default)]
+    pub join: Vec<String>,
+    // Create a tap interface for every joined network (Linux only)
+    #[serde(default)]
+    pub tap: bool,
+    #[serde(default)]
+    pub paths: Paths,
+    #[serde(default)]
+    pub physical: Vec<Physical>,
+    #[serde(default)]
+    pub interfaces: Interfaces,
+    #[serde(default)]
+    pub moons: Vec<Moon>,
+    // Custom planet file replacing ZeroTier's default planet
+    pub planet: Option<String>,
     pub networks: Vec<Network>,
 }
 
+impl Config {
+    /// Returns the ports to listen on, a random port is 0
+    pub fn ports(&self) -> Vec<u16> {
+        let mut ports = vec![self.port, self.secondary_port];
+        ports.extend(&self.extra_ports);
+        if self.random_port {
+            ports.push(0);
+        }
+        let mut distinct = Vec::new();
+        for port in ports {
+            if !distinct.contains(&port) {
+                distinct.push(port);
+            }
+        }
+        distinct
+    }
+
+    /// Returns ids of the networks to join
+    pub fn join_networks(&self) -> Fallible<Vec<u64>> {
+        let mut networks = Vec::new();
+        for id in &self.join {
+            let mut bytes = [0u8; 8];
+            hex::decode_to_slice(id, &mut bytes)?;
+            networks.push(u64::from_be_bytes(bytes));
+        }
+        Ok(networks)
+    }
+}
+
 fn default_port() -> u16 { 9994 }
 fn default_secondary_port() -> u16 { 29995 }
+fn default_bind() -> Vec<IpAddr> { vec![Ipv4Addr::UNSPECIFIED.into(), Ipv6Addr::UNSPECIFIED.into()] }
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone, Default)]
+pub struct Paths {
+    // Physical networks never used to reach peers
+    #[serde(default)]
+    pub forbid: Vec<IpNetwork>,
+    // Physical addresses of peers by ZeroTier address
+    #[serde(default, rename = "static")]
+    pub static_paths: BTreeMap<String, Vec<SocketAddr>>,
+}
+
+// Local interfaces whose addresses are advertised to peers as direct paths
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct Interfaces {
+    // Seconds between checks for changed addresses
+    #[serde(default = "default_interfaces_interval", deserialize_with = "deserialize_interval")]
+    pub interval: u64,
+    // Prefixes of interface names to use, all interfaces when empty
+    #[serde(default)]
+    pub include: Vec<String>,
+    // Prefixes of interface names to never use, e.g. our own tap devices
+    #[serde(default = "default_interfaces_exclude")]
+    pub exclude: Vec<String>,
+}
+
+impl Interfaces {
+    /// Returns true if addresses of the interface should be used
+    pub fn matches(&self, name: &str) -> bool {
+        (self.include.is_empty() || self.include.iter().any(|p| name.starts_with(p.as_str())))
+            && !self.exclude.iter().any(|p| name.starts_with(p.as_str()))
+    }
+}
+
+impl Default for Interfaces {
+    fn default() -> Self {
+        Self {
+            interval: default_interfaces_interval(),
+            include: Vec::new(),
+            exclude: default_interfaces_exclude(),
+        }
+    }
+}
+
+fn default_interfaces_interval() -> u64 { 60 }
+// Interfaces would be looked up on every poll with an interval of 0
+fn deserialize_interval<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<u64, D::Error> {
+    match u64::deserialize(deserializer)? {
+        0 => Err(serde::de::Error::custom("interfaces.interval must be at least 1 second")),
+        interval => Ok(interval),
+    }
+}
+fn default_interfaces_exclude() -> Vec<String> {
+    ["lo", "zt", "tun", "tap", "docker", "veth", "virbr"].iter().map(|p| p.to_string()).collect()
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct Moon {
+    // World id of the moon (16 hex characters)
+    pub id: String,
+    // Address of one of the moon's roots, used to fetch the moon the first time
+    pub seed: Option<String>,
+}
+
+impl Moon {
+    /// Returns world id and seed of the moon
+    pub fn ids(&self) -> Fallible<(u64, u64)> {
+        let mut id = [0u8; 8];
+        hex::decode_to_slice(&self.id, &mut id)?;
+        let mut seed = [0u8; 8];
+        if let Some(s) = &self.seed {
+            hex::decode_to_slice(s, &mut seed[3..])?;
+        }
+        Ok((u64::from_be_bytes(id), u64::from_be_bytes(seed)))
+    }
+}
+
+// Settings for a physical network, like physical in ZeroTierOne's local.conf
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct Physical {
+    pub network: IpNetwork,
+    // Never use the network to reach peers
+    #[serde(default)]
+    pub blacklist: bool,
+    pub mtu: Option<u32>,
+    // Skip encryption on the network, peers have to use the same id
+    pub trusted_path_id: Option<u64>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct Audit {
+    pub path: String,
+    #[serde(default = "default_audit_max_size")]
+    pub max_size: u64,
+    #[serde(default = "default_audit_keep")]
+    pub keep: usize,
+}
+
+fn default_audit_max_size() -> u64 { 10 * 1024 * 1024 }
+fn default_audit_keep() -> usize { 5 }
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone, Default)]
+pub struct RequestLimits {
+    queue_size: Option<usize>,
+    address_burst: Option<u32>,
+    address_interval: Option<i64>,
+    identity_burst: Option<u32>,
+    identity_interval: Option<i64>,
+}
+
+impl From<RequestLimits> for zt::controller::RequestLimits {
+    fn from(limits: RequestLimits) -> Self {
+        let defaults = Self::default();
+        Self {
+            queue_size: limits.queue_size.unwrap_or(defaults.queue_size),
+            address_burst: limits.address_burst.unwrap_or(defaults.address_burst),
+            address_interval: limits.address_interval.unwrap_or(defaults.address_interval),
+            identity_burst: limits.identity_burst.unwrap_or(defaults.identity_burst),
+            identity_interval: limits.identity_interval.unwrap_or(defaults.identity_interval),
+            max_tracked: defaults.max_tracked,
+        }
+    }
+}
 
 #[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
 pub struct Network {
@@ -35,6 +220,8 @@ pub struct Network {
     multicast_recipient_limit: u64,
     #[serde(default = "default_mtu")]
     mtu: u16,
+    #[serde(default = "default_credential_time_max_delta")]
+    credential_time_max_delta: u64,
     dns: Option<DNS>,
     members: Vec<Member>,
     rules: Option<Vec<BTreeMap<String, String>>>,
@@ -45,6 +232,7 @@ fn default_public() -> bool { false }
 fn default_broadcast() -> bool { true }
 fn default_multicast() -> u64 { 32 }
 fn default_mtu() -> u16 { 2800 }
+fn default_credential_time_max_delta() -> u64 { 7200000 }
 
 #[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
 struct Route {
@@ -62,6 +250,9 @@ struct DNS {
 struct Member {
     address: String,
     ip: Option<String>,
+    // Unix timestamps (in seconds) limiting when the membership is valid
+    not_before: Option<i64>,
+    expires_at: Option<i64>,
 }
 
 impl Member {
@@ -102,6 +293,8 @@ impl Member {
         Ok(zt::controller::Member {
             address: address,
             ip: Ipv4Network::new(ip, network.prefix())?,
+            not_before: self.not_before.map(|ts| ts * 1000),
+            expires_at: self.expires_at.map(|ts| ts * 1000),
         })
     }
 }
@@ -138,6 +331,7 @@ impl TryInto<zt::controller::Network> for Network {
             broadcast: self.broadcast,
             multicast_recipient_limit: self.multicast_recipient_limit,
             mtu: self.mtu,
+            credential_time_max_delta: self.credential_time_max_delta,
             members: members,
         })
     }
@@ -147,6 +341,23 @@ impl TryInto<zt::controller::Network> for Network {
 pub mod tests {
     use super::*;
 
+    #[test]
+    fn test_sample_config() -> Fallible<()> {
+        let conf: Config = serde_yaml::from_str(include_str!("../.sample.yaml"))?;
+        assert_eq!(conf.physical.len(), 2);
+        assert_eq!(conf.ports(), vec![9994, 29995, 0]);
+        assert_eq!(conf.bind.len(), 2);
+        assert!(conf.interfaces.matches("eth0"));
+        assert!(!conf.interfaces.matches("zt11ca36addb"));
+        assert!(serde_yaml::from_str::<Interfaces>("interval: 0").is_err());
+        assert_eq!(conf.join_networks()?, vec![0xba7a59abb06f066b]);
+        assert_eq!(conf.moons[0].ids()?, (0x00000099e5a948c2, 0x99e5a948c2));
+        for n in conf.networks {
+            let _: zt::controller::Network = n.try_into()?;
+        }
+        Ok(())
+    }
+
     #[test]
     fn test_into_zt_member_without_ip() -> Fallible<()> {
         let network = Ipv4Network::from_str("100.100.0.0/20")?;
@@ -154,6 +365,8 @@ pub mod tests {
         let member = Member {
             address: "aabbccddee".to_string(),
             ip: None,
+            not_before: None,
+            expires_at: None,
         };
 
         let zt_member = member.try_into_zt_member(&network)?;
@@ -171,6 +384,8 @@ pub mod tests {
         let member = Member {
             address: "aabbccddee".to_string(),
             ip: Some("100.100.0.10".to_string()),
+            not_before: None,
+            expires_at: None,
         };
 
         let zt_member = member.try_into_zt_member(&network)?;
@@ -181,6 +396,25 @@ pub mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_into_zt_member_with_expiry() -> Fallible<()> {
+        let network = Ipv4Network::from_str("100.100.0.0/24")?;
+
+        let member = Member {
+            address: "aabbccddee".to_string(),
+            ip: None,
+            not_before: Some(1650000000),
+            expires_at: Some(1660000000),
+        };
+
+        let zt_member = member.try_into_zt_member(&network)?;
+
+        assert_eq!(zt_member.not_before, Some(1650000000000));
+        assert_eq!(zt_member.expires_at, Some(1660000000000));
+
+        Ok(())
+    }
+
     #[test]
     fn test_into_zt_network_without_id() -> Fallible<()> {
         let network = Network {
@@ -193,14 +427,19 @@ pub mod tests {
             broadcast: true,
             multicast_recipient_limit: 32,
             mtu: 2800,
+            credential_time_max_delta: 7200000,
             members: vec![
                 Member {
                     address: "aabbccddee".to_string(),
                     ip: None,
+                    not_before: None,
+                    expires_at: None,
                 },
                 Member {
                     address: "a1b2c3d4e5".to_string(),
                     ip: Some("100.100.0.10".to_string()),
+                    not_before: None,
+                    expires_at: None,
                 },
             ],
             dns: None,
@@ -227,14 +466,19 @@ pub mod tests {
             broadcast: true,
             multicast_recipient_limit: 32,
             mtu: 2800,
+            credential_time_max_delta: 7200000,
             members: vec![
                 Member {
                     address: "aabbccddee".to_string(),
                     ip: None,
+                    not_before: None,
+                    expires_at: None,
                 },
                 Member {
                     address: "a1b2c3d4e5".to_string(),
                     ip: Some("100.100.0.10".to_string()),
+                    not_before: None,
+                    expires_at: None,
                 },
             ],
             dns: None,
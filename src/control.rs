@@ -0,0 +1,86 @@
+use std::io::{BufRead, BufReader, Read, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::time::Duration;
+use zt::core::{NodeHandle, Peer};
+use failure::Fallible;
+
+const TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Answers queries on a unix socket from a thread of its own
+///
+/// A client writes a single command line and reads the JSON response until
+/// the daemon closes the connection. Queries are passed to the node through
+/// its handle.
+pub fn serve(path: &str, node: NodeHandle) -> Fallible<()> {
+    // Remove the socket left behind by a previous run
+    let _ = std::fs::remove_file(path);
+    let listener = UnixListener::bind(path)?;
+
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let res = stream.map_err(|e| e.into()).and_then(|stream| handle(stream, &node));
+            if let Err(error) = res {
+                println!("control request failed: {}", error);
+            }
+        }
+    });
+    Ok(())
+}
+
+fn handle(stream: UnixStream, node: &NodeHandle) -> Fallible<()> {
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+
+    let mut command = String::new();
+    BufReader::new(&stream).read_line(&mut command)?;
+
+    let response = match command.trim() {
+        "peers" => serde_json::to_vec(&node.peers()?)?,
+        cmd => serde_json::to_vec(&serde_json::json!({ "error": format!("unknown command {}", cmd) }))?,
+    };
+    (&stream).write_all(&response)?;
+
+    Ok(())
+}
+
+// Sends a command to a running daemon and returns the response
+fn request(path: &str, command: &str) -> Fallible<Vec<u8>> {
+    let mut stream = UnixStream::connect(path)?;
+    stream.set_read_timeout(Some(TIMEOUT * 5))?;
+    stream.write_all(format!("{}\n", command).as_bytes())?;
+
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response)?;
+    Ok(response)
+}
+
+/// Prints the peers of a running daemon
+pub fn print_peers(path: &str) -> Fallible<()> {
+    let mut peers: Vec<Peer> = serde_json::from_slice(&request(path, "peers")?)?;
+    peers.sort_by_key(|p| p.address);
+
+    println!("{:<10} {:<6} {:<8} {:>7}  {}", "address", "role", "version", "latency", "paths");
+    for peer in peers {
+        let version = match peer.version {
+            Some((major, minor, rev)) => format!("{}.{}.{}", major, minor, rev),
+            None => "-".to_string(),
+        };
+        let latency = match peer.latency {
+            Some(latency) => format!("{}ms", latency),
+            None => "-".to_string(),
+        };
+        let paths: Vec<String> = peer.paths.iter()
+            .filter(|p| !p.expired)
+            .map(|p| format!("{}{}", p.address, if p.preferred { "*" } else { "" }))
+            .collect();
+        println!(
+            "{:010x} {:<6} {:<8} {:>7}  {}",
+            peer.address,
+            format!("{:?}", peer.role).to_uppercase(),
+            version,
+            latency,
+            paths.join(","),
+        );
+    }
+    Ok(())
+}
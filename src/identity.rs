@@ -1,60 +1,101 @@
-use std::cell::Cell;
-use zt::core::{StateProvider, StateObject, StateError};
+use std::cell::RefCell;
+use std::io::ErrorKind;
+use zt::core::{StateProvider, StateObject, StateError, DirectoryState};
+use zt::identity::{Identity, IdentityError};
 use failure::Fallible;
 
-const IDENTITY_LENGTH: usize = 270;
-
+/// Serves the node identity from a file
+///
+/// All other state objects are kept in an optional state directory, without
+/// it they are not persisted. A planet file can be set to use a custom planet
+/// instead of ZeroTier's default one.
 pub struct IdentityState {
     identity_file: Box<String>,
-    identity: Cell<[u8; IDENTITY_LENGTH]>,
+    identity: RefCell<Option<Identity>>,
+    planet_file: Option<String>,
+    state: Option<DirectoryState>,
 }
 
 impl IdentityState {
-    pub fn new(identity_file: &str) -> Self {
+    pub fn new(identity_file: &str, state_path: Option<&str>) -> Self {
         Self {
             identity_file: Box::new(identity_file.to_string()),
-            identity: Cell::new([0u8; IDENTITY_LENGTH]),
+            identity: RefCell::new(None),
+            planet_file: None,
+            state: state_path.map(DirectoryState::new),
         }
     }
 
-    fn get_identity(&self) -> Fallible<[u8; IDENTITY_LENGTH]> {
-        if self.identity.get()[..5] != [0,0,0,0,0] {
-            return Ok(self.identity.get());
+    /// Serves the planet from a file, it is never overwritten by the node
+    pub fn set_planet_file(&mut self, planet_file: &str) {
+        self.planet_file = Some(planet_file.to_string());
+    }
+
+    fn get_identity(&self) -> Fallible<Identity> {
+        if let Some(identity) = self.identity.borrow().as_ref() {
+            return Ok(identity.clone());
+        }
+        // A missing identity is generated by the node, other failures must
+        // not let it replace the identity
+        let id = match std::fs::read_to_string(self.identity_file.as_str()) {
+            Ok(id) => id,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Err(StateError::NotFound.into()),
+            Err(err) => return Err(err.into()),
+        };
+        let identity: Identity = id.parse()?;
+        if !identity.has_secret() {
+            return Err(IdentityError::NoSecret.into());
         }
-        // Reading identity from identity_file
-        let id = std::fs::read_to_string(self.identity_file.as_str())?;
-        // Copying the contents of the file to a buffer and setting
-        // the contents as the identity
-        let mut buf = [0u8; IDENTITY_LENGTH];
-        buf.copy_from_slice(&id.trim().as_bytes()[..IDENTITY_LENGTH]);
-        self.identity.set(buf);
-        Ok(self.identity.get())
+        *self.identity.borrow_mut() = Some(identity.clone());
+        Ok(identity)
     }
 
     fn set_identity(&self, buf: &[u8]) -> Fallible<()> {
-        if buf.len() <= IDENTITY_LENGTH {
-            std::fs::write(self.identity_file.as_str(), buf)?;
-            self.identity.get().copy_from_slice(buf);
-            return Ok(());
-        }
-        Err(StateError::TooLong.into())
+        let identity: Identity = std::str::from_utf8(buf)?.parse()?;
+        std::fs::write(self.identity_file.as_str(), buf)?;
+        *self.identity.borrow_mut() = Some(identity);
+        Ok(())
     }
 }
 
 impl StateProvider for IdentityState {
-    fn get_state(&self, object_type: StateObject) -> Fallible<Vec<u8>> {
-        let res = match object_type {
-            StateObject::PublicIdentity => Vec::from(&self.get_identity()?[..141]),
-            StateObject::SecretIdentity => Vec::from(&self.get_identity()?[..]),
+    fn get_state(&self, object_type: StateObject, id: &[u64; 2]) -> Fallible<Vec<u8>> {
+        if let (StateObject::Planet, Some(planet_file)) = (object_type, &self.planet_file) {
+            return Ok(std::fs::read(planet_file)?);
+        }
+        let res = match (object_type, &self.state) {
+            (StateObject::PublicIdentity, _) => self.get_identity()?.to_public().to_string().into_bytes(),
+            (StateObject::SecretIdentity, _) => self.get_identity()?.to_string().into_bytes(),
+            (_, Some(state)) => state.get_state(object_type, id)?,
+            (_, None) => return Err(StateError::NotFound.into()),
         };
         Ok(res)
     }
 
-    fn set_state(&self, object_type: StateObject, data: &[u8]) -> Fallible<()> {
-        let _res = match object_type {
-            StateObject::SecretIdentity => self.set_identity(data),
-            _ => Err(StateError::NotFound.into()),
-        };
-        Ok(())
+    fn set_state(&self, object_type: StateObject, id: &[u64; 2], data: &[u8]) -> Fallible<()> {
+        match (object_type, &self.state) {
+            (StateObject::SecretIdentity, _) => self.set_identity(data),
+            (StateObject::PublicIdentity, _) => Ok(()),
+            (StateObject::Planet, _) if self.planet_file.is_some() => Ok(()),
+            (_, Some(state)) => state.set_state(object_type, id, data),
+            // Not persisted without a state directory
+            (_, None) => Ok(()),
+        }
+    }
+
+    fn delete_state(&self, object_type: StateObject, id: &[u64; 2]) -> Fallible<()> {
+        match (object_type, &self.state) {
+            (StateObject::PublicIdentity, _) | (StateObject::SecretIdentity, _) => Ok(()),
+            (StateObject::Planet, _) if self.planet_file.is_some() => Ok(()),
+            (_, Some(state)) => state.delete_state(object_type, id),
+            (_, None) => Ok(()),
+        }
+    }
+
+    fn list_state(&self, object_type: StateObject) -> Fallible<Vec<[u64; 2]>> {
+        match &self.state {
+            Some(state) => state.list_state(object_type),
+            None => Ok(Vec::new()),
+        }
     }
 }
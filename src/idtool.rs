@@ -0,0 +1,138 @@
+use clap::Subcommand;
+use zt::identity::Identity;
+use zt::controller::ZeroTierSigner;
+use failure::Fallible;
+use std::fs::{OpenOptions, Permissions};
+use std::io::Write;
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+
+/// Identity commands, like zerotier-idtool
+///
+/// Identities are given as a path or directly as a string.
+#[derive(Subcommand, Debug)]
+pub enum IdtoolCommand {
+    /// Generate a new identity, it is printed if no path is given
+    Generate {
+        secret: Option<String>,
+        public: Option<String>,
+    },
+    /// Print the public part of an identity
+    Getpublic {
+        identity: String,
+    },
+    /// Check that the address was derived from the identity's key
+    Validate {
+        identity: String,
+    },
+    /// Sign a file, prints the signature in hex
+    Sign {
+        identity: String,
+        file: String,
+    },
+    /// Check a signature of a file
+    Verify {
+        identity: String,
+        file: String,
+        signature: String,
+    },
+}
+
+/// Runs the command, returns false if validating or verifying failed
+pub fn run(command: IdtoolCommand) -> Fallible<bool> {
+    match command {
+        IdtoolCommand::Generate { secret, public } => {
+            let identity = Identity::generate();
+            match secret {
+                Some(path) => {
+                    write_secret(&path, &identity.to_string())?;
+                    println!("{} written", path);
+                },
+                None => println!("{}", identity),
+            }
+            if let Some(path) = public {
+                std::fs::write(&path, identity.to_public().to_string())?;
+                println!("{} written", path);
+            }
+        },
+        IdtoolCommand::Getpublic { identity } => {
+            println!("{}", read_identity(&identity)?.to_public());
+        },
+        IdtoolCommand::Validate { identity } => {
+            if !read_identity(&identity)?.validate() {
+                println!("{} FAILED validation", identity);
+                return Ok(false);
+            }
+            println!("{} is a valid identity", identity);
+        },
+        IdtoolCommand::Sign { identity, file } => {
+            let signature = read_identity(&identity)?.sign(&std::fs::read(file)?)?;
+            println!("{}", hex::encode(signature));
+        },
+        IdtoolCommand::Verify { identity, file, signature } => {
+            let signature = hex::decode(signature.trim())?;
+            if !read_identity(&identity)?.verify(&std::fs::read(&file)?, &signature) {
+                println!("{} signature check FAILED", file);
+                return Ok(false);
+            }
+            println!("{} signature valid", file);
+        },
+    }
+    Ok(true)
+}
+
+// Writes a secret identity, nobody but us should be able to read it. The
+// file is created that way and existing files are restricted before anything
+// is written to them.
+fn write_secret(path: &str, secret: &str) -> Fallible<()> {
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)?;
+    file.set_permissions(Permissions::from_mode(0o600))?;
+    file.write_all(secret.as_bytes())?;
+    Ok(())
+}
+
+// Identities given directly start with the address, like getIdFromArg in
+// zerotier-idtool
+fn read_identity(arg: &str) -> Fallible<Identity> {
+    if arg.len() > 32 && arg.as_bytes()[10] == b':' {
+        return arg.parse();
+    }
+    std::fs::read_to_string(arg)?.parse()
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_identity() -> Fallible<()> {
+        let public = "894f8955a6:0:2ca7d749ec20a750b6189cf1f51a5f7db67bbed6218cbae506946c01e267cd05d6e4bd580af21231b7edd03eb04a086a43a14cfca67b19a1cc4484e5ad142034";
+        assert_eq!(read_identity(public)?.address, 0x894f8955a6);
+
+        let path = std::env::temp_dir().join(format!("rztc-idtool-{}", std::process::id()));
+        std::fs::write(&path, format!("{}\n", public))?;
+        let identity = read_identity(path.to_str().unwrap());
+        std::fs::remove_file(&path)?;
+        assert_eq!(identity?.address, 0x894f8955a6);
+        Ok(())
+    }
+
+    #[test]
+    fn test_write_secret() -> Fallible<()> {
+        let path = std::env::temp_dir().join(format!("rztc-idtool-secret-{}", std::process::id()));
+        std::fs::write(&path, "")?;
+        std::fs::set_permissions(&path, Permissions::from_mode(0o644))?;
+
+        write_secret(path.to_str().unwrap(), "secret")?;
+        let mode = std::fs::metadata(&path)?.permissions().mode();
+        let data = std::fs::read_to_string(&path)?;
+        std::fs::remove_file(&path)?;
+        assert_eq!(mode & 0o777, 0o600);
+        assert_eq!(data, "secret");
+        Ok(())
+    }
+}
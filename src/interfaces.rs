@@ -0,0 +1,188 @@
+use std::ffi::CStr;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::{Duration, Instant};
+use zt::core::Node;
+use failure::Fallible;
+use crate::config;
+
+/// Returns addresses of all local interfaces with the interface's name
+pub fn addresses() -> io::Result<Vec<(String, IpAddr)>> {
+    let mut ifaddrs: *mut libc::ifaddrs = std::ptr::null_mut();
+    if unsafe { libc::getifaddrs(&mut ifaddrs) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let mut addresses = Vec::new();
+    let mut ifa = ifaddrs;
+    while !ifa.is_null() {
+        let entry = unsafe { &*ifa };
+        ifa = entry.ifa_next;
+        if entry.ifa_addr.is_null() {
+            continue;
+        }
+        let ip = match unsafe { (*entry.ifa_addr).sa_family } as libc::c_int {
+            libc::AF_INET => {
+                let sin = unsafe { &*(entry.ifa_addr as *const libc::sockaddr_in) };
+                IpAddr::V4(Ipv4Addr::from(u32::from_be(sin.sin_addr.s_addr)))
+            },
+            libc::AF_INET6 => {
+                let sin6 = unsafe { &*(entry.ifa_addr as *const libc::sockaddr_in6) };
+                IpAddr::V6(Ipv6Addr::from(sin6.sin6_addr.s6_addr))
+            },
+            _ => continue,
+        };
+        let name = unsafe { CStr::from_ptr(entry.ifa_name) };
+        addresses.push((name.to_string_lossy().into_owned(), ip));
+    }
+
+    unsafe { libc::freeifaddrs(ifaddrs) };
+    Ok(addresses)
+}
+
+/// Returns true if peers could reach the address directly
+///
+/// Link local addresses are left out as they need an interface to be used.
+pub fn is_advertised(ip: &IpAddr) -> bool {
+    if ip.is_loopback() || ip.is_unspecified() || ip.is_multicast() {
+        return false;
+    }
+    match ip {
+        IpAddr::V4(v4) => !v4.is_link_local() && !v4.is_broadcast(),
+        IpAddr::V6(v6) => !v6.is_unicast_link_local(),
+    }
+}
+
+/// Keeps the local interface addresses known by the node up to date
+pub struct Watcher {
+    conf: config::Interfaces,
+    // Addresses of the sockets of the node
+    locals: Vec<SocketAddr>,
+    // Addresses the node was told about, sorted
+    addresses: Vec<IpAddr>,
+    next: Instant,
+}
+
+impl Watcher {
+    /// Creates a watcher advertising the addresses sockets are bound to, the
+    /// first check is due right away
+    pub fn new(conf: &config::Interfaces, locals: &[SocketAddr]) -> Self {
+        Self {
+            conf: conf.clone(),
+            locals: locals.to_vec(),
+            addresses: Vec::new(),
+            next: Instant::now(),
+        }
+    }
+
+    /// Looks up the addresses once the interval has passed and re-registers
+    /// them with the node if they changed
+    pub fn check(&mut self, node: &Node) -> Fallible<()> {
+        let now = Instant::now();
+        if now < self.next {
+            return Ok(());
+        }
+        self.next = now + Duration::from_secs(self.conf.interval);
+
+        let current = self.filter(addresses()?);
+        if current == self.addresses {
+            return Ok(());
+        }
+
+        node.clear_local_interface_addresses();
+        for address in self.bound(&current) {
+            // ZeroTier rejects addresses it never uses for paths, the others
+            // are still registered
+            if let Err(error) = node.add_local_interface_address(&address) {
+                println!("unable to add local interface address {}: {}", address, error);
+            }
+        }
+        println!("local interface addresses: {:?}", current);
+        self.addresses = current;
+        Ok(())
+    }
+
+    // Returns the sorted addresses to advertise
+    fn filter(&self, addresses: Vec<(String, IpAddr)>) -> Vec<IpAddr> {
+        let mut filtered: Vec<IpAddr> = addresses.into_iter()
+            .filter(|(name, ip)| self.conf.matches(name) && is_advertised(ip))
+            .map(|(_, ip)| ip)
+            .collect();
+        filtered.sort();
+        filtered.dedup();
+        filtered
+    }
+
+    // Returns the addresses with the port of every socket bound to them,
+    // sockets bound to an unspecified address take all addresses of its family
+    fn bound(&self, addresses: &[IpAddr]) -> Vec<SocketAddr> {
+        let mut bound = Vec::new();
+        for ip in addresses {
+            for local in &self.locals {
+                let matches = local.ip() == *ip || (local.ip().is_unspecified() && local.is_ipv4() == ip.is_ipv4());
+                let address = SocketAddr::new(*ip, local.port());
+                if matches && !bound.contains(&address) {
+                    bound.push(address);
+                }
+            }
+        }
+        bound
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_advertised() -> Fallible<()> {
+        assert!(is_advertised(&"2001:db8::1".parse()?));
+        assert!(is_advertised(&"fd00::1".parse()?));
+        assert!(is_advertised(&"192.168.1.10".parse()?));
+        assert!(!is_advertised(&"fe80::1".parse()?));
+        assert!(!is_advertised(&"169.254.1.1".parse()?));
+        assert!(!is_advertised(&"127.0.0.1".parse()?));
+        assert!(!is_advertised(&"ff02::1".parse()?));
+        Ok(())
+    }
+
+    #[test]
+    fn test_filter() -> Fallible<()> {
+        let conf: config::Interfaces = serde_yaml::from_str("include: [eth, wlan]\nexclude: [eth1]")?;
+        let watcher = Watcher::new(&conf, &["0.0.0.0:9994".parse()?]);
+
+        let addresses = vec![
+            ("eth0".to_string(), "2001:db8::1".parse()?),
+            ("eth0".to_string(), "192.0.2.1".parse()?),
+            ("eth0".to_string(), "fe80::1".parse()?),
+            ("eth1".to_string(), "192.0.2.2".parse()?),
+            ("wlan0".to_string(), "192.0.2.1".parse()?),
+            ("docker0".to_string(), "172.17.0.1".parse()?),
+        ];
+        let expected: Vec<IpAddr> = vec!["192.0.2.1".parse()?, "2001:db8::1".parse()?];
+        assert_eq!(watcher.filter(addresses), expected);
+        Ok(())
+    }
+
+    #[test]
+    fn test_bound() -> Fallible<()> {
+        let conf = config::Interfaces::default();
+        let locals: Vec<SocketAddr> = vec![
+            "192.0.2.1:9994".parse()?,
+            "[::]:9994".parse()?,
+            "192.0.2.1:29995".parse()?,
+            "[::]:29995".parse()?,
+        ];
+        let watcher = Watcher::new(&conf, &locals);
+
+        let addresses: Vec<IpAddr> = vec!["192.0.2.1".parse()?, "192.0.2.2".parse()?, "2001:db8::1".parse()?];
+        let expected: Vec<SocketAddr> = vec![
+            "192.0.2.1:9994".parse()?,
+            "192.0.2.1:29995".parse()?,
+            "[2001:db8::1]:9994".parse()?,
+            "[2001:db8::1]:29995".parse()?,
+        ];
+        assert_eq!(watcher.bound(&addresses), expected);
+        Ok(())
+    }
+}
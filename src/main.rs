@@ -1,41 +1,183 @@
 extern crate hex;
 extern crate serde;
 extern crate serde_yaml;
+extern crate serde_json;
 extern crate clap;
 
+#[cfg(not(feature = "tokio"))]
 mod phy;
+mod socket;
+mod interfaces;
 mod identity;
+mod idtool;
 mod config;
+mod audit;
+mod control;
+mod paths;
+#[cfg(target_os = "linux")]
+mod netlink;
+#[cfg(target_os = "linux")]
+mod tap;
+#[cfg(feature = "tokio")]
+mod runtime;
 
+#[cfg(not(feature = "tokio"))]
 use std::time::{SystemTime, UNIX_EPOCH};
-use zt::core::Node;
-use zt::controller::Controller;
+use std::pin::Pin;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::mpsc::{channel, Receiver};
+#[cfg(target_os = "linux")]
+use tap::Taps;
+use zt::core::{Node, NodeBuilder, Event, NodeError, PhyProvider};
+use zt::controller::{Controller, FileStore, Network};
+use zt::world::World;
+#[cfg(not(feature = "tokio"))]
 use phy::Phy;
 use identity::IdentityState;
+use audit::RotatingAuditSink;
+use paths::ConfigPathPolicy;
+use mio::{Registry, Token};
 use failure::Fallible;
-use clap::Parser;
+use clap::{CommandFactory, ErrorKind, Parser, Subcommand};
+use idtool::IdtoolCommand;
+
+/// Token of the waker for commands sent through node handles, Phy uses the
+/// ones below
+pub const COMMAND_TOKEN: Token = Token(512);
 
 pub struct NodeRunner {
-    node: Node,
-    phy: Phy,
+    node: Pin<Box<Node>>,
+    events: Receiver<Event>,
+    #[cfg(target_os = "linux")]
+    taps: Option<Taps>,
+    interfaces: interfaces::Watcher,
+    online: bool,
 }
 
 impl NodeRunner {
-    pub fn new(node: Node, phy: Phy) -> Self {
-        Self {
+    /// Sets up the node from the config, other event sources like the
+    /// control socket and tap devices are registered with the registry
+    ///
+    /// Local interface addresses are advertised if phy has a socket bound to
+    /// them, `locals` are the addresses of its sockets.
+    pub fn new(conf: &config::Config, registry: &Registry, phy: Arc<dyn PhyProvider>, locals: &[SocketAddr]) -> Fallible<Self> {
+        let mut identity_state = IdentityState::new(conf.identity_path.as_str(), conf.state_path.as_deref());
+        if let Some(path) = &conf.planet {
+            // Fail early instead of the node silently falling back to the default planet
+            let planet = World::from_bytes(&std::fs::read(path)?)?;
+            println!("using planet {:016x} from {}", planet.id, path);
+            identity_state.set_planet_file(path);
+        }
+
+        // Handlers are registered before the node starts so Event::Up isn't missed
+        let (tx, events) = channel();
+        let node = NodeBuilder::new(Box::new(identity_state), phy)
+            .event_handler(Box::new(tx))
+            .path_policy(Box::new(ConfigPathPolicy::new(&conf.paths, &conf.physical)?))
+            .build()?;
+
+        // Commands sent through node handles are run when the waker fires
+        let waker = Arc::new(mio::Waker::new(registry, COMMAND_TOKEN)?);
+        node.set_command_waker(Arc::new(move || {
+            let _ = waker.wake();
+        }));
+
+        let networks = conf.join_networks()?;
+        #[cfg(target_os = "linux")]
+        let taps = match conf.tap {
+            true => Some(Taps::new(&node, registry, &networks)?),
+            false => None,
+        };
+
+        init_controller(&node, conf)?;
+
+        println!("libzerotierone v{}", node.version());
+
+        // Moons orbited before are restored from the state directory by the node
+        for moon in &conf.moons {
+            let (id, seed) = moon.ids()?;
+            node.orbit(id, seed)?;
+            println!("orbiting moon {:016x}", id);
+        }
+
+        for p in conf.physical.iter().filter(|p| !p.blacklist) {
+            node.set_physical_path_config(&p.network, p.mtu.unwrap_or(0), p.trusted_path_id.unwrap_or(0))?;
+        }
+
+        for nwid in networks {
+            node.join(nwid)?;
+            println!("joined network {:016x}", nwid);
+        }
+
+        if let Some(path) = &conf.control_path {
+            control::serve(path, node.handle())?;
+        }
+
+        let online = node.is_online();
+        Ok(Self {
             node: node,
-            phy: phy,
+            events: events,
+            #[cfg(target_os = "linux")]
+            taps: taps,
+            interfaces: interfaces::Watcher::new(&conf.interfaces, locals),
+            online: online,
+        })
+    }
+
+    // Handles event sources other than the sockets in Phy
+    fn handle_ready(&self, token: Token) {
+        if token == COMMAND_TOKEN {
+            self.node.process_commands();
+            return;
+        }
+        #[cfg(target_os = "linux")]
+        if let Some(taps) = &self.taps {
+            taps.process(token, &self.node);
+        }
+    }
+
+    // Handles events emitted by the node since the last call, and changes of
+    // the local interface addresses
+    fn handle_events(&mut self) -> Fallible<()> {
+        while let Ok(event) = self.events.try_recv() {
+            match event {
+                Event::IdentityCollision => return Err(NodeError::IdentityCollision.into()),
+                Event::Trace(msg) => println!("trace: {}", msg),
+                Event::UserMessage(msg) => println!("user message from {:010x} (type {})", msg.origin, msg.type_id),
+                Event::RemoteTrace(trace) => println!("remote trace from {:010x}: {:?}", trace.origin, trace.event()),
+                _ => (),
+            }
         }
+
+        #[cfg(target_os = "linux")]
+        if let Some(taps) = &self.taps {
+            taps.sync_multicast(&self.node);
+        }
+
+        if let Err(error) = self.interfaces.check(&self.node) {
+            println!("unable to check local interface addresses: {}", error);
+        }
+
+        let online = self.node.is_online();
+        if self.online != online {
+            println!("node status changed: {}", if online { "online" } else { "offline" });
+            self.online = online;
+        }
+        Ok(())
     }
 
-    pub fn run(&mut self) -> Fallible<()> {
-        let mut online = self.node.is_online();
+    #[cfg(not(feature = "tokio"))]
+    pub fn run(&mut self, phy: &mut Phy) -> Fallible<()> {
         let mut next: i64 = 0;
 
         loop {
             // Poll sockets for incoming packets
-            if let Err(error) = self.phy.poll(&self.node) {
-                println!("poll failed: {}", error);
+            match phy.poll(&self.node) {
+                Ok(ready) => for token in ready {
+                    self.handle_ready(token);
+                },
+                Err(error) => println!("poll failed: {}", error),
             }
 
             // Get current time in milliseconds since epoch
@@ -43,26 +185,35 @@ impl NodeRunner {
             let now: i64 = now.as_millis().try_into().unwrap();
             // Process background tasks in node
             if next < now {
-                match self.node.process_background_tasks(&self.phy) {
+                match self.node.process_background_tasks() {
                     Ok(next_deadline) => next = next_deadline,
                     Err(err) => println!("process_background_tasks failed: {}", err),
                 }
             }
 
-            let node_online = self.node.is_online();
-            if online != node_online {
-                println!("node status changed: {}", if node_online { "online" } else { "offline" });
-                online = node_online;
-            }
+            self.handle_events()?;
         }
     }
 }
 
-fn init_controller(node: &mut Node, conf: &config::Config) -> Fallible<()> {
-    let mut controller = Controller::new();
+fn init_controller(node: &Node, conf: &config::Config) -> Fallible<()> {
+    let mut controller = match &conf.store_path {
+        Some(path) => Controller::with_store(Box::new(FileStore::new(path)?)),
+        None => Controller::new(),
+    };
+    controller.set_request_limits(conf.request_limits.clone().into());
+
+    if let Some(audit) = &conf.audit {
+        let sink = RotatingAuditSink::open(audit.path.as_str(), audit.max_size, audit.keep)?;
+        controller.set_audit_sink(Box::new(sink));
+    }
 
     for n in &conf.networks {
-        controller.add_network(n.clone().try_into()?);
+        let network: Network = n.clone().try_into()?;
+        let name = network.name.clone();
+        if !controller.seed_network(network)? {
+            println!("network {} is already in the store, keeping the stored one", name);
+        }
     }
 
     node.register_controller(Box::new(controller))?;
@@ -70,40 +221,66 @@ fn init_controller(node: &mut Node, conf: &config::Config) -> Fallible<()> {
     Ok(())
 }
 
+#[cfg(not(feature = "tokio"))]
 fn run(conf: config::Config) -> Fallible<()> {
-    let identity_state = IdentityState::new(conf.identity_path.as_str());
-
-    let mut node = Node::new(Box::new(identity_state))?;
-    init_controller(&mut node, &conf)?;
-
-    println!("libzerotierone v{}", node.version());
-
-    let phy = Phy::new(conf.port, conf.secondary_port).unwrap();
-    let mut runner = NodeRunner::new(node, phy);
-
-    runner.run()?;
+    let mut phy = Phy::new(&conf.bind, &conf.ports())?;
+    let mut runner = NodeRunner::new(&conf, phy.registry(), phy.sockets(), &phy.locals())?;
+    runner.run(&mut phy)
+}
 
-    Ok(())
+#[cfg(feature = "tokio")]
+fn run(conf: config::Config) -> Fallible<()> {
+    runtime::run(conf)
 }
 
 /// ZeroTier network controller
 #[derive(Parser, Debug)]
 #[clap(author, version, about, long_about = None)]
 struct Args {
-    /// Path to config file
+    /// Path to config file, required by all commands but idtool
     #[clap(short, long)]
-    config: String,
+    config: Option<String>,
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Run the controller (default)
+    Run,
+    /// List peers of a running controller
+    Peers,
+    /// Generate, validate and sign with identities
+    Idtool {
+        #[clap(subcommand)]
+        command: IdtoolCommand,
+    },
+}
+
+fn read_config(path: &Option<String>) -> config::Config {
+    let path = match path {
+        Some(path) => path,
+        None => Args::command().error(ErrorKind::MissingRequiredArgument, "--config is required").exit(),
+    };
+    serde_yaml::from_str(
+		&*std::fs::read_to_string(path.as_str())
+			.expect(&format!("Could not open file {}", path)))
+		.expect("Could not parse the configuration yaml file")
 }
 
 fn main() -> Fallible<()> {
     let args = Args::parse();
 
-    let conf: config::Config = serde_yaml::from_str(
-		&*std::fs::read_to_string(args.config.as_str())
-			.expect(&format!("Could not open file {}", args.config)))
-		.expect("Could not parse the configuration yaml file");
-
-    run(conf)?;
+    match args.command.unwrap_or(Command::Run) {
+        Command::Run => run(read_config(&args.config))?,
+        Command::Peers => match &read_config(&args.config).control_path {
+            Some(path) => control::print_peers(path)?,
+            None => println!("control_path is not set in {}", args.config.unwrap_or_default()),
+        },
+        Command::Idtool { command } => if !idtool::run(command)? {
+            std::process::exit(1);
+        },
+    }
 
     Ok(())
 }
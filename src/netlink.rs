@@ -0,0 +1,262 @@
+use std::io::{Error, ErrorKind};
+use std::net::IpAddr;
+use std::os::unix::io::RawFd;
+use ipnetwork::IpNetwork;
+use zt::core::Route;
+use failure::Fallible;
+
+const NLMSG_HDRLEN: usize = 16;
+
+/// Minimal rtnetlink client for configuring interfaces
+pub struct Netlink {
+    fd: RawFd,
+    seq: u32,
+}
+
+impl Netlink {
+    pub fn new() -> Fallible<Self> {
+        let fd = unsafe {
+            libc::socket(libc::AF_NETLINK, libc::SOCK_RAW | libc::SOCK_CLOEXEC, libc::NETLINK_ROUTE)
+        };
+        if fd < 0 {
+            return Err(Error::last_os_error().into());
+        }
+        Ok(Self { fd: fd, seq: 0 })
+    }
+
+    /// Sets MAC address and MTU of an interface and brings it up
+    pub fn set_link(&mut self, index: u32, mac: &[u8; 6], mtu: u32) -> Fallible<()> {
+        let mut msg = Message::new(libc::RTM_NEWLINK, 0);
+        // struct ifinfomsg
+        msg.push_u8(libc::AF_UNSPEC as u8);
+        msg.push_u8(0);
+        msg.push_u16(0);
+        msg.push_u32(index);
+        msg.push_u32(libc::IFF_UP as u32); // ifi_flags
+        msg.push_u32(libc::IFF_UP as u32); // ifi_change
+        msg.attr(libc::IFLA_ADDRESS, mac);
+        msg.attr(libc::IFLA_MTU, &mtu.to_ne_bytes());
+        self.request(msg)
+    }
+
+    pub fn add_address(&mut self, index: u32, address: &IpNetwork) -> Fallible<()> {
+        let msg = address_message(libc::RTM_NEWADDR, libc::NLM_F_CREATE | libc::NLM_F_REPLACE, index, address);
+        self.request(msg)
+    }
+
+    pub fn del_address(&mut self, index: u32, address: &IpNetwork) -> Fallible<()> {
+        let msg = address_message(libc::RTM_DELADDR, 0, index, address);
+        self.request(msg)
+    }
+
+    pub fn add_route(&mut self, index: u32, route: &Route) -> Fallible<()> {
+        let msg = route_message(libc::RTM_NEWROUTE, libc::NLM_F_CREATE | libc::NLM_F_REPLACE, index, route);
+        self.request(msg)
+    }
+
+    pub fn del_route(&mut self, index: u32, route: &Route) -> Fallible<()> {
+        let msg = route_message(libc::RTM_DELROUTE, 0, index, route);
+        self.request(msg)
+    }
+
+    // Sends a request and waits for the kernel to acknowledge it
+    fn request(&mut self, mut msg: Message) -> Fallible<()> {
+        self.seq = self.seq.wrapping_add(1);
+        let buf = msg.finalize(self.seq);
+
+        let mut addr: libc::sockaddr_nl = unsafe { std::mem::zeroed() };
+        addr.nl_family = libc::AF_NETLINK as u16;
+        let sent = unsafe {
+            libc::sendto(
+                self.fd,
+                buf.as_ptr() as *const _,
+                buf.len(),
+                0,
+                &addr as *const _ as *const libc::sockaddr,
+                std::mem::size_of::<libc::sockaddr_nl>() as u32,
+            )
+        };
+        if sent < 0 {
+            return Err(Error::last_os_error().into());
+        }
+
+        let mut reply = [0u8; 4096];
+        loop {
+            let len = unsafe { libc::recv(self.fd, reply.as_mut_ptr() as *mut _, reply.len(), 0) };
+            if len < 0 {
+                return Err(Error::last_os_error().into());
+            }
+            if let Some(res) = parse_ack(&reply[..len as usize], self.seq) {
+                return res;
+            }
+        }
+    }
+}
+
+impl Drop for Netlink {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.fd) };
+    }
+}
+
+// Builds a netlink message, the header is written in finalize()
+struct Message {
+    msg_type: u16,
+    flags: u16,
+    buf: Vec<u8>,
+}
+
+impl Message {
+    fn new(msg_type: u16, flags: libc::c_int) -> Self {
+        Self {
+            msg_type: msg_type,
+            flags: (libc::NLM_F_REQUEST | libc::NLM_F_ACK | flags) as u16,
+            buf: Vec::new(),
+        }
+    }
+
+    fn push_u8(&mut self, v: u8) { self.buf.push(v); }
+    fn push_u16(&mut self, v: u16) { self.buf.extend_from_slice(&v.to_ne_bytes()); }
+    fn push_u32(&mut self, v: u32) { self.buf.extend_from_slice(&v.to_ne_bytes()); }
+
+    // Appends an attribute padded to 4 bytes
+    fn attr(&mut self, attr_type: u16, data: &[u8]) {
+        self.push_u16((4 + data.len()) as u16);
+        self.push_u16(attr_type);
+        self.buf.extend_from_slice(data);
+        while self.buf.len() % 4 != 0 {
+            self.buf.push(0);
+        }
+    }
+
+    fn finalize(&mut self, seq: u32) -> Vec<u8> {
+        let mut msg = Vec::with_capacity(NLMSG_HDRLEN + self.buf.len());
+        msg.extend_from_slice(&((NLMSG_HDRLEN + self.buf.len()) as u32).to_ne_bytes());
+        msg.extend_from_slice(&self.msg_type.to_ne_bytes());
+        msg.extend_from_slice(&self.flags.to_ne_bytes());
+        msg.extend_from_slice(&seq.to_ne_bytes());
+        msg.extend_from_slice(&0u32.to_ne_bytes()); // pid, the kernel fills it in
+        msg.extend_from_slice(&self.buf);
+        msg
+    }
+}
+
+fn family(ip: &IpAddr) -> u8 {
+    match ip {
+        IpAddr::V4(_) => libc::AF_INET as u8,
+        IpAddr::V6(_) => libc::AF_INET6 as u8,
+    }
+}
+
+fn ip_bytes(ip: &IpAddr) -> Vec<u8> {
+    match ip {
+        IpAddr::V4(ip) => ip.octets().to_vec(),
+        IpAddr::V6(ip) => ip.octets().to_vec(),
+    }
+}
+
+fn address_message(msg_type: u16, flags: libc::c_int, index: u32, address: &IpNetwork) -> Message {
+    let ip = address.ip();
+    let mut msg = Message::new(msg_type, flags);
+    // struct ifaddrmsg
+    msg.push_u8(family(&ip));
+    msg.push_u8(address.prefix());
+    msg.push_u8(0); // flags
+    msg.push_u8(libc::RT_SCOPE_UNIVERSE);
+    msg.push_u32(index);
+    msg.attr(libc::IFA_LOCAL, &ip_bytes(&ip));
+    msg.attr(libc::IFA_ADDRESS, &ip_bytes(&ip));
+    msg
+}
+
+fn route_message(msg_type: u16, flags: libc::c_int, index: u32, route: &Route) -> Message {
+    let target = route.target.network();
+    let mut msg = Message::new(msg_type, flags);
+    // struct rtmsg
+    msg.push_u8(family(&target));
+    msg.push_u8(route.target.prefix()); // dst_len
+    msg.push_u8(0); // src_len
+    msg.push_u8(0); // tos
+    msg.push_u8(libc::RT_TABLE_MAIN);
+    msg.push_u8(libc::RTPROT_STATIC);
+    msg.push_u8(if route.via.is_some() { libc::RT_SCOPE_UNIVERSE } else { libc::RT_SCOPE_LINK });
+    msg.push_u8(libc::RTN_UNICAST);
+    msg.push_u32(0); // flags
+    if route.target.prefix() > 0 {
+        msg.attr(libc::RTA_DST, &ip_bytes(&target));
+    }
+    if let Some(via) = &route.via {
+        msg.attr(libc::RTA_GATEWAY, &ip_bytes(via));
+    }
+    msg.attr(libc::RTA_OIF, &index.to_ne_bytes());
+    if route.metric > 0 {
+        msg.attr(libc::RTA_PRIORITY, &(route.metric as u32).to_ne_bytes());
+    }
+    msg
+}
+
+// Looks for the acknowledgement of a request in a reply, returns None if the
+// reply doesn't contain it
+fn parse_ack(mut buf: &[u8], seq: u32) -> Option<Fallible<()>> {
+    while buf.len() >= NLMSG_HDRLEN {
+        let len = u32::from_ne_bytes([buf[0], buf[1], buf[2], buf[3]]) as usize;
+        let msg_type = u16::from_ne_bytes([buf[4], buf[5]]);
+        let msg_seq = u32::from_ne_bytes([buf[8], buf[9], buf[10], buf[11]]);
+        if len < NLMSG_HDRLEN || len > buf.len() {
+            return Some(Err(Error::new(ErrorKind::InvalidData, "truncated netlink message").into()));
+        }
+        if msg_type == libc::NLMSG_ERROR as u16 && msg_seq == seq && len >= NLMSG_HDRLEN + 4 {
+            let code = i32::from_ne_bytes([buf[16], buf[17], buf[18], buf[19]]);
+            return match code {
+                0 => Some(Ok(())),
+                _ => Some(Err(Error::from_raw_os_error(-code).into())),
+            };
+        }
+        buf = &buf[std::cmp::min((len + 3) & !3, buf.len())..];
+    }
+    None
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn test_route_message() {
+        let route = Route {
+            target: IpNetwork::from_str("10.0.0.0/8").unwrap(),
+            via: Some(IpAddr::from_str("10.147.20.1").unwrap()),
+            flags: 0,
+            metric: 0,
+        };
+        let buf = route_message(libc::RTM_NEWROUTE, 0, 7, &route).finalize(1);
+
+        // header + rtmsg + dst + gateway + oif
+        const RTMSG_LEN: usize = 12;
+        assert_eq!(buf.len(), NLMSG_HDRLEN + RTMSG_LEN + 8 * 3);
+        assert_eq!(u32::from_ne_bytes([buf[0], buf[1], buf[2], buf[3]]) as usize, buf.len());
+        assert_eq!(buf[NLMSG_HDRLEN], libc::AF_INET as u8);
+        assert_eq!(buf[NLMSG_HDRLEN + 1], 8);
+        assert_eq!(&buf[NLMSG_HDRLEN + RTMSG_LEN + 4..][..4], &[10, 0, 0, 0]);
+        assert_eq!(&buf[NLMSG_HDRLEN + RTMSG_LEN + 12..][..4], &[10, 147, 20, 1]);
+    }
+
+    #[test]
+    fn test_parse_ack() {
+        let mut ack = Vec::new();
+        ack.extend_from_slice(&36u32.to_ne_bytes());
+        ack.extend_from_slice(&(libc::NLMSG_ERROR as u16).to_ne_bytes());
+        ack.extend_from_slice(&0u16.to_ne_bytes());
+        ack.extend_from_slice(&5u32.to_ne_bytes());
+        ack.extend_from_slice(&0u32.to_ne_bytes());
+        ack.extend_from_slice(&(-libc::EEXIST).to_ne_bytes());
+        ack.extend_from_slice(&[0u8; 16]);
+
+        assert!(parse_ack(&ack, 4).is_none());
+        assert!(matches!(parse_ack(&ack, 5), Some(Err(_))));
+
+        ack[16..20].copy_from_slice(&0i32.to_ne_bytes());
+        assert!(matches!(parse_ack(&ack, 5), Some(Ok(()))));
+    }
+}
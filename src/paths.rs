@@ -0,0 +1,84 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use ipnetwork::IpNetwork;
+use zt::core::{PathPolicy, AddressFamily};
+use failure::Fallible;
+use crate::config;
+
+/// Path policy from the `paths` section of the config and blacklisted
+/// `physical` networks
+pub struct ConfigPathPolicy {
+    forbid: Vec<IpNetwork>,
+    static_paths: HashMap<u64, Vec<SocketAddr>>,
+}
+
+impl ConfigPathPolicy {
+    pub fn new(conf: &config::Paths, physical: &[config::Physical]) -> Fallible<Self> {
+        let mut static_paths = HashMap::new();
+        for (address, paths) in &conf.static_paths {
+            let mut bytes = [0u8; 8];
+            hex::decode_to_slice(address, &mut bytes[3..])?;
+            static_paths.insert(u64::from_be_bytes(bytes), paths.clone());
+        }
+
+        let blacklist = physical.iter()
+            .filter(|p| p.blacklist)
+            .map(|p| p.network);
+
+        Ok(Self {
+            forbid: conf.forbid.iter().cloned().chain(blacklist).collect(),
+            static_paths: static_paths,
+        })
+    }
+}
+
+impl PathPolicy for ConfigPathPolicy {
+    fn check_path(&self, _address: u64, _socket: i64, path: &SocketAddr) -> bool {
+        !self.forbid.iter().any(|network| network.contains(path.ip()))
+    }
+
+    fn lookup_path(&self, address: u64, family: AddressFamily) -> Option<SocketAddr> {
+        self.static_paths.get(&address)?
+            .iter()
+            .find(|path| family.matches(path))
+            .cloned()
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn test_config_path_policy() -> Fallible<()> {
+        let conf: config::Paths = serde_yaml::from_str(r#"
+forbid:
+  - 10.0.0.0/8
+  - fd00::/8
+static:
+  99e5a948c2:
+    - "[2001:db8::1]:9993"
+    - 192.0.2.1:9993
+"#)?;
+        let physical: Vec<config::Physical> = serde_yaml::from_str(r#"
+- network: 172.16.0.0/12
+  blacklist: true
+- network: 192.168.0.0/16
+  mtu: 1400
+"#)?;
+        let policy = ConfigPathPolicy::new(&conf, &physical)?;
+
+        assert!(!policy.check_path(0x99e5a948c2, 0, &SocketAddr::from_str("10.1.2.3:9993")?));
+        assert!(!policy.check_path(0x99e5a948c2, 0, &SocketAddr::from_str("[fd00::1]:9993")?));
+        assert!(!policy.check_path(0x99e5a948c2, 0, &SocketAddr::from_str("172.16.1.1:9993")?));
+        assert!(policy.check_path(0x99e5a948c2, 0, &SocketAddr::from_str("192.168.1.1:9993")?));
+        assert!(policy.check_path(0x99e5a948c2, 0, &SocketAddr::from_str("192.0.2.1:9993")?));
+
+        assert_eq!(policy.lookup_path(0x99e5a948c2, AddressFamily::Ipv4), Some(SocketAddr::from_str("192.0.2.1:9993")?));
+        assert_eq!(policy.lookup_path(0x99e5a948c2, AddressFamily::Any), Some(SocketAddr::from_str("[2001:db8::1]:9993")?));
+        assert_eq!(policy.lookup_path(0x1122334455, AddressFamily::Any), None);
+
+        Ok(())
+    }
+}
@@ -1,72 +1,127 @@
 extern crate mio;
 
-use std::net::SocketAddr;
+use std::io::ErrorKind;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
 use mio::net::UdpSocket;
-use mio::{Events, Interest, Poll, Token};
+use mio::{Events, Interest, Poll, Registry, Token};
 use core::time::Duration;
 use zt::core::{Node, PhyProvider};
 use failure::Fallible;
+use crate::socket::{self, Bound, SendErrors};
 
-const MAIN: Token = Token(0);
-const SECONDARY: Token = Token(1);
+// Failed reads after which a socket is given up on until its next event
+const RECV_ERRORS_MAX: usize = 64;
+
+/// UDP sockets the node sends its packets through, their ids are used as
+/// tokens
+#[derive(Debug)]
+pub struct Sockets {
+    sockets: Vec<Bound<UdpSocket>>,
+    errors: SendErrors,
+}
 
 #[derive(Debug)]
 pub struct Phy {
-    main: UdpSocket,
-    secondary: UdpSocket,
+    sockets: Arc<Sockets>,
     poll: Poll,
 }
 
 impl Phy {
-    pub fn new(port: u16, secondary_port: u16) -> Fallible<Phy> {
-        let mut main = UdpSocket::bind(format!("0.0.0.0:{}", port).parse()?)?;
-        let mut secondary = UdpSocket::bind(format!("0.0.0.0:{}", secondary_port).parse()?)?;
+    /// Binds every port on every address
+    pub fn new(addresses: &[IpAddr], ports: &[u16]) -> Fallible<Phy> {
         let poll = Poll::new()?;
+        let mut sockets = Vec::new();
 
-        poll.registry().register(&mut main, MAIN, Interest::READABLE)?;
-        poll.registry().register(&mut secondary, SECONDARY, Interest::READABLE)?;
+        for bound in socket::bind_all(addresses, ports)? {
+            let mut socket = UdpSocket::from_std(bound.socket);
+            poll.registry().register(&mut socket, Token(bound.id as usize), Interest::READABLE)?;
+            sockets.push(Bound { id: bound.id, local: bound.local, socket: socket });
+        }
 
         Ok(Self {
-            main: main,
-            secondary: secondary,
+            sockets: Arc::new(Sockets {
+                sockets: sockets,
+                errors: SendErrors::default(),
+            }),
             poll: poll,
         })
     }
 
-    pub fn poll(&mut self, node: &Node) -> Fallible<()> {
+    /// Sockets to hand to the node
+    pub fn sockets(&self) -> Arc<Sockets> {
+        self.sockets.clone()
+    }
+
+    /// Addresses the sockets are bound to, random ports included
+    pub fn locals(&self) -> Vec<SocketAddr> {
+        socket::locals(&self.sockets.sockets)
+    }
+
+    /// Registry to register other event sources with, they must use tokens
+    /// that aren't used by Phy
+    pub fn registry(&self) -> &Registry {
+        self.poll.registry()
+    }
+
+    /// Polls sockets and passes incoming packets to node
+    ///
+    /// Returns tokens of other event sources that are ready
+    pub fn poll(&mut self, node: &Node) -> Fallible<Vec<Token>> {
         let mut events = Events::with_capacity(1024);
+        let mut ready = Vec::new();
+        let mut buf = [0u8; 2048];
 
         self.poll.poll(&mut events, Some(Duration::from_millis(200)))?;
 
         for event in &events {
-            let mut buf = [0u8; 2048];
-            if let Some((len, addr)) = match event.token() {
-                MAIN => Some(self.main.recv_from(&mut buf).unwrap()),
-                SECONDARY => Some(self.secondary.recv_from(&mut buf).unwrap()),
-                Token(_) => None,
-            } {
-                let res = node.process_wire_packet(self, &buf, len, &addr, event.token().0 as i64);
+            let bound = match self.sockets.sockets.iter().find(|s| Token(s.id as usize) == event.token()) {
+                Some(bound) => bound,
+                None => {
+                    ready.push(event.token());
+                    continue;
+                },
+            };
+
+            // Events are edge triggered, so the socket is read until it's empty
+            let mut errors = 0;
+            loop {
+                let (len, addr) = match bound.socket.recv_from(&mut buf) {
+                    Ok(received) => received,
+                    Err(error) if error.kind() == ErrorKind::WouldBlock => break,
+                    // ICMP errors of earlier sends are reported once, the
+                    // packets after them can still be read
+                    Err(error) if socket::is_icmp_error(error.kind()) => continue,
+                    Err(error) if error.kind() == ErrorKind::Interrupted => continue,
+                    // Other errors might be temporary as well, the socket
+                    // wouldn't be read again if it was left with packets
+                    Err(error) => {
+                        errors += 1;
+                        if errors == RECV_ERRORS_MAX {
+                            println!("unable to receive on {}: {} ({} failed reads)", bound.local, error, errors);
+                            break;
+                        }
+                        continue;
+                    },
+                };
+                let res = node.process_wire_packet(&buf, len, &addr, bound.id);
                 if let Err(error) = res {
                     println!("process_wire_packet failed: {}", error);
                 }
             }
         };
-        Ok(())
+        Ok(ready)
     }
 }
 
-impl PhyProvider for Phy {
+impl PhyProvider for Sockets {
     fn send(&self, address: &SocketAddr, socket: i64, buf: &[u8]) -> usize {
-        match Token(socket as usize) {
-            MAIN => self.main.send_to(buf, address.clone()).unwrap(),
-            SECONDARY => self.secondary.send_to(buf, address.clone()).unwrap(),
-            Token(_) => 0usize,
-        }
+        let sockets = socket::route(&self.sockets, address, socket).take(1);
+        socket::send(sockets, &self.errors, address, |s| s.send_to(buf, *address))
     }
 
     fn send_all(&self, address: &SocketAddr, buf: &[u8]) -> usize {
-        self.main.send_to(buf, address.clone()).unwrap();
-        self.secondary.send_to(buf, address.clone()).unwrap();
-        0
+        let sockets = socket::route(&self.sockets, address, -1);
+        socket::send(sockets, &self.errors, address, |s| s.send_to(buf, *address))
     }
 }
@@ -0,0 +1,232 @@
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::rc::Rc;
+use std::sync::Arc;
+use std::task::{Context, Poll as TaskPoll};
+use std::time::Duration;
+use tokio::io::ReadBuf;
+use tokio::io::unix::AsyncFd;
+use tokio::net::UdpSocket;
+use tokio::sync::Notify;
+use tokio::task::{JoinHandle, LocalSet};
+use mio::{Events, Poll, Registry, Token};
+use zt::core::{Controller, PhyProvider};
+use failure::Fallible;
+use crate::{config, NodeRunner};
+use crate::socket::{self, Bound, SendErrors};
+
+// How often the controller's background tasks run
+const CONTROLLER_INTERVAL: Duration = Duration::from_secs(1);
+
+/// UDP sockets owned by tokio the node sends its packets through
+pub struct AsyncSockets {
+    sockets: Vec<Bound<UdpSocket>>,
+    errors: SendErrors,
+}
+
+impl AsyncSockets {
+    // Receives a packet from whichever socket has one first
+    fn poll_recv_from(&self, cx: &mut Context<'_>, buf: &mut [u8]) -> TaskPoll<io::Result<(i64, usize, SocketAddr)>> {
+        for bound in &self.sockets {
+            let mut read = ReadBuf::new(buf);
+            if let TaskPoll::Ready(res) = bound.socket.poll_recv_from(cx, &mut read) {
+                return TaskPoll::Ready(res.map(|addr| (bound.id, read.filled().len(), addr)));
+            }
+        }
+        TaskPoll::Pending
+    }
+}
+
+/// Phy driven by tokio
+///
+/// Other event sources (tap devices, the command waker) are still registered
+/// with a mio registry, the mio poll itself is then watched by tokio.
+pub struct AsyncPhy {
+    sockets: Arc<AsyncSockets>,
+    poll: Poll,
+}
+
+impl AsyncPhy {
+    /// Binds the sockets, has to be called from within the runtime
+    pub async fn new(addresses: &[IpAddr], ports: &[u16]) -> Fallible<Self> {
+        let mut sockets = Vec::new();
+        for bound in socket::bind_all(addresses, ports)? {
+            let socket = UdpSocket::from_std(bound.socket)?;
+            sockets.push(Bound { id: bound.id, local: bound.local, socket: socket });
+        }
+
+        Ok(Self {
+            sockets: Arc::new(AsyncSockets {
+                sockets: sockets,
+                errors: SendErrors::default(),
+            }),
+            poll: Poll::new()?,
+        })
+    }
+
+    /// Sockets to hand to the node
+    pub fn sockets(&self) -> Arc<AsyncSockets> {
+        self.sockets.clone()
+    }
+
+    /// Addresses the sockets are bound to, random ports included
+    pub fn locals(&self) -> Vec<SocketAddr> {
+        socket::locals(&self.sockets.sockets)
+    }
+
+    /// Registry to register other event sources with
+    pub fn registry(&self) -> &Registry {
+        self.poll.registry()
+    }
+
+    // Returns tokens of other event sources that are ready without blocking
+    fn ready(&mut self) -> Fallible<Vec<Token>> {
+        let mut events = Events::with_capacity(128);
+        self.poll.poll(&mut events, Some(Duration::ZERO))?;
+        Ok(events.iter().map(|e| e.token()).collect())
+    }
+}
+
+impl PhyProvider for AsyncSockets {
+    fn send(&self, address: &SocketAddr, socket: i64, buf: &[u8]) -> usize {
+        // Sending never waits, a full socket buffer drops the packet like
+        // any other loss on the way
+        let sockets = socket::route(&self.sockets, address, socket).take(1);
+        socket::send(sockets, &self.errors, address, |s| s.try_send_to(buf, *address))
+    }
+
+    fn send_all(&self, address: &SocketAddr, buf: &[u8]) -> usize {
+        let sockets = socket::route(&self.sockets, address, -1);
+        socket::send(sockets, &self.errors, address, |s| s.try_send_to(buf, *address))
+    }
+}
+
+/// Drives the node until it fails
+///
+/// Instead of polling with a timeout the driver sleeps until either a packet
+/// arrives, another event source is ready or the deadline returned by the
+/// node for its background tasks is reached.
+///
+/// The controller runs in a task of its own, so answering requests or
+/// refreshing and revoking credentials doesn't hold up packets. It is woken
+/// up after every packet to answer the requests it carried.
+///
+/// Node isn't Send so the driver has to run on a `LocalSet`, other services
+/// can be spawned on the same set with `tokio::task::spawn_local`.
+pub async fn drive(mut runner: NodeRunner, mut phy: AsyncPhy) -> Fallible<()> {
+    let poll_fd = AsyncFd::new(phy.poll.as_raw_fd() as RawFd)?;
+    let sockets = phy.sockets();
+    let mut buf = [0u8; 2048];
+    let mut next = tokio::time::Instant::now();
+
+    let requests = Rc::new(Notify::new());
+    let _controller = runner.node.detach_controller().map(|controller| {
+        AbortOnDrop(tokio::task::spawn_local(run_controller(controller, requests.clone())))
+    });
+
+    loop {
+        tokio::select! {
+            res = std::future::poll_fn(|cx| sockets.poll_recv_from(cx, &mut buf)) => {
+                match res {
+                    Ok((id, len, addr)) => {
+                        match runner.node.process_wire_packet(&buf, len, &addr, id) {
+                            Ok(deadline) => next = deadline_instant(deadline),
+                            Err(error) => println!("process_wire_packet failed: {}", error),
+                        }
+                        requests.notify_one();
+                    },
+                    // ICMP errors of earlier sends are reported once
+                    Err(error) if socket::is_icmp_error(error.kind()) => (),
+                    Err(error) => println!("unable to receive: {}", error),
+                }
+            },
+            guard = poll_fd.readable() => {
+                let mut guard = guard?;
+                for token in phy.ready()? {
+                    runner.handle_ready(token);
+                }
+                guard.clear_ready();
+            },
+            _ = tokio::time::sleep_until(next) => {
+                match runner.node.process_background_tasks() {
+                    Ok(deadline) => next = deadline_instant(deadline),
+                    Err(error) => {
+                        println!("process_background_tasks failed: {}", error);
+                        next = tokio::time::Instant::now() + Duration::from_secs(1);
+                    },
+                }
+            },
+        }
+
+        runner.handle_events()?;
+    }
+}
+
+// Answers controller requests whenever woken up and runs the controller's
+// background tasks in between
+async fn run_controller(controller: Rc<dyn Controller>, requests: Rc<Notify>) {
+    let mut background = tokio::time::interval(CONTROLLER_INTERVAL);
+    background.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+    loop {
+        tokio::select! {
+            _ = requests.notified() => {
+                if let Err(error) = controller.process_requests() {
+                    println!("controller failed to process requests: {}", error);
+                }
+            },
+            _ = background.tick() => {
+                if let Err(error) = controller.process_background_tasks() {
+                    println!("controller background tasks failed: {}", error);
+                }
+            },
+        }
+    }
+}
+
+// Aborts a task once the driver returns
+struct AbortOnDrop(JoinHandle<()>);
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+// Converts a deadline in milliseconds since epoch into an instant
+fn deadline_instant(deadline: i64) -> tokio::time::Instant {
+    let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).expect("unable to get time in millis");
+    tokio::time::Instant::now() + deadline_wait(deadline, now.as_millis() as i64)
+}
+
+// Returns how long to wait for a deadline, both in milliseconds since epoch
+fn deadline_wait(deadline: i64, now: i64) -> Duration {
+    Duration::from_millis(deadline.saturating_sub(now).max(0) as u64)
+}
+
+/// Runs the node on a single threaded tokio runtime
+pub fn run(conf: config::Config) -> Fallible<()> {
+    let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build()?;
+    let local = LocalSet::new();
+    local.block_on(&runtime, async {
+        let phy = AsyncPhy::new(&conf.bind, &conf.ports()).await?;
+        let runner = NodeRunner::new(&conf, phy.registry(), phy.sockets(), &phy.locals())?;
+        drive(runner, phy).await
+    })
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    #[test]
+    fn test_deadline_wait() {
+        let now = 1650367222104;
+
+        // Deadlines in the past are due right away
+        assert_eq!(deadline_wait(now - 1000, now), Duration::ZERO);
+        assert_eq!(deadline_wait(i64::MIN, now), Duration::ZERO);
+        assert_eq!(deadline_wait(now + 5000, now), Duration::from_millis(5000));
+    }
+}
@@ -0,0 +1,190 @@
+use std::io;
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+use std::sync::atomic::{AtomicU64, Ordering};
+use socket2::{Domain, Protocol, Socket, Type};
+use failure::Fallible;
+
+/// Socket with the id the node knows it by
+#[derive(Debug)]
+pub struct Bound<S> {
+    pub id: i64,
+    pub local: SocketAddr,
+    pub socket: S,
+}
+
+/// Binds a non-blocking UDP socket
+///
+/// IPv6 sockets only receive IPv6 packets, so they can share their port with
+/// an IPv4 socket.
+pub fn bind(address: SocketAddr) -> io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::for_address(address), Type::DGRAM, Some(Protocol::UDP))?;
+    if address.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+    socket.set_nonblocking(true)?;
+    socket.bind(&address.into())?;
+    Ok(socket.into())
+}
+
+/// Binds a socket for every port on every address, the sockets' ids are
+/// their positions
+///
+/// Port 0 binds a random port on the first address, the same port is then
+/// used on the other addresses. IPv6 addresses are skipped on hosts without
+/// IPv6.
+pub fn bind_all(addresses: &[IpAddr], ports: &[u16]) -> Fallible<Vec<Bound<UdpSocket>>> {
+    let mut sockets = Vec::new();
+    for port in ports {
+        let mut port = *port;
+        for ip in addresses {
+            let local = SocketAddr::new(*ip, port);
+            match bind(local) {
+                Ok(socket) => {
+                    let local = socket.local_addr()?;
+                    port = local.port();
+                    sockets.push(Bound { id: sockets.len() as i64, local: local, socket: socket });
+                },
+                Err(error) => {
+                    println!("unable to bind {}: {}", local, error);
+                    if ip.is_ipv4() {
+                        return Err(error.into());
+                    }
+                },
+            }
+        }
+    }
+    Ok(sockets)
+}
+
+/// Returns the addresses the sockets are bound to, random ports included
+pub fn locals<S>(sockets: &[Bound<S>]) -> Vec<SocketAddr> {
+    sockets.iter().map(|s| s.local).collect()
+}
+
+/// Returns the sockets a packet to the address is sent through
+///
+/// The node passes -1 as id to send through all sockets. Sockets of the
+/// other address family are never used.
+pub fn route<'a, S>(sockets: &'a [Bound<S>], address: &SocketAddr, id: i64) -> impl Iterator<Item = &'a S> {
+    let ipv6 = address.is_ipv6();
+    sockets.iter()
+        .filter(move |s| s.local.is_ipv6() == ipv6 && (id == -1 || s.id == id))
+        .map(|s| &s.socket)
+}
+
+/// Returns true for errors receiving caused by ICMP errors of packets sent
+/// before from the socket
+pub fn is_icmp_error(kind: io::ErrorKind) -> bool {
+    matches!(
+        kind,
+        io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::HostUnreachable
+            | io::ErrorKind::NetworkUnreachable
+    )
+}
+
+/// Counts failed sends
+///
+/// Most failures are temporary (e.g. unreachable hosts, ICMP errors of
+/// earlier packets or full socket buffers), so the packet is dropped instead
+/// of stopping the node.
+#[derive(Debug, Default)]
+pub struct SendErrors(AtomicU64);
+
+impl SendErrors {
+    /// Counts a failure, it's only logged when the count is a power of two so
+    /// a host failing all the time doesn't flood the log
+    pub fn record(&self, address: &SocketAddr, error: &io::Error) {
+        let count = self.0.fetch_add(1, Ordering::Relaxed) + 1;
+        if count.is_power_of_two() {
+            println!("unable to send to {}: {} ({} failed sends)", address, error, count);
+        }
+    }
+}
+
+/// Sends a packet through each socket, returns the bytes sent in total
+pub fn send<'a, S: 'a>(
+    sockets: impl Iterator<Item = &'a S>,
+    errors: &SendErrors,
+    address: &SocketAddr,
+    send_to: impl Fn(&S) -> io::Result<usize>,
+) -> usize {
+    let mut sent = 0;
+    for socket in sockets {
+        match send_to(socket) {
+            Ok(len) => sent += len,
+            Err(error) => errors.record(address, &error),
+        }
+    }
+    sent
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use std::net::Ipv6Addr;
+
+    #[test]
+    fn test_route() -> Fallible<()> {
+        let sockets = vec![
+            Bound { id: 0, local: "0.0.0.0:9994".parse()?, socket: "main" },
+            Bound { id: 1, local: "[::]:9994".parse()?, socket: "main v6" },
+            Bound { id: 2, local: "0.0.0.0:29995".parse()?, socket: "secondary" },
+            Bound { id: 3, local: "[::]:29995".parse()?, socket: "secondary v6" },
+        ];
+        let v4: SocketAddr = "192.0.2.1:9993".parse()?;
+        let v6: SocketAddr = "[2001:db8::1]:9993".parse()?;
+
+        assert_eq!(route(&sockets, &v4, 2).collect::<Vec<_>>(), vec![&"secondary"]);
+        assert_eq!(route(&sockets, &v4, -1).collect::<Vec<_>>(), vec![&"main", &"secondary"]);
+        assert_eq!(route(&sockets, &v6, -1).collect::<Vec<_>>(), vec![&"main v6", &"secondary v6"]);
+        // The socket can't reach the address
+        assert_eq!(route(&sockets, &v6, 0).count(), 0);
+        assert_eq!(route(&sockets, &v4, 4).count(), 0);
+        assert_eq!(locals(&sockets)[3], "[::]:29995".parse()?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_send() -> Fallible<()> {
+        let errors = SendErrors::default();
+        let address: SocketAddr = "192.0.2.1:9993".parse()?;
+        let results: Vec<io::Result<usize>> = vec![
+            Ok(100),
+            Err(io::ErrorKind::ConnectionRefused.into()),
+            Ok(100),
+        ];
+
+        assert_eq!(send(results.iter(), &errors, &address, |res| match res {
+            Ok(len) => Ok(*len),
+            Err(error) => Err(error.kind().into()),
+        }), 200);
+        assert_eq!(errors.0.load(Ordering::Relaxed), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_bind_random_port() -> Fallible<()> {
+        let sockets = bind_all(&["127.0.0.1".parse()?, "::1".parse()?], &[0])?;
+        let port = sockets[0].local.port();
+        assert_ne!(port, 0);
+        for (id, s) in sockets.iter().enumerate() {
+            assert_eq!(s.id, id as i64);
+            assert_eq!(s.local.port(), port);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_bind_both_families() -> Fallible<()> {
+        let v4 = bind("0.0.0.0:0".parse()?)?;
+        let port = v4.local_addr()?.port();
+        match bind((Ipv6Addr::UNSPECIFIED, port).into()) {
+            Ok(v6) => assert_eq!(v6.local_addr()?.port(), port),
+            // Hosts without IPv6 fail differently
+            Err(error) => assert_ne!(error.kind(), io::ErrorKind::AddrInUse),
+        }
+        Ok(())
+    }
+}
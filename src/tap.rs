@@ -0,0 +1,449 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{ErrorKind, Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+use std::net::IpAddr;
+use std::rc::Rc;
+use mio::{Interest, Registry, Token};
+use mio::unix::SourceFd;
+use ipnetwork::IpNetwork;
+use zt::core::{
+    Node, Frame, FrameHandler, NetworkConfigHandler, NetworkConfigOperation, VirtualNetworkConfig, Route,
+};
+use crate::netlink::Netlink;
+use failure::Fallible;
+
+// From linux/if_tun.h
+const TUNSETIFF: libc::c_ulong = 0x400454ca;
+const IFF_TAP: libc::c_short = 0x0002;
+const IFF_NO_PI: libc::c_short = 0x1000;
+
+const ETHERNET_HEADER_LEN: usize = 14;
+
+const BROADCAST_MAC: u64 = 0xffffffffffff;
+
+/// First mio token used for tap devices, Phy uses the ones below
+pub const TAP_TOKEN_BASE: usize = 1024;
+
+#[repr(C)]
+struct IfReq {
+    name: [u8; libc::IFNAMSIZ],
+    flags: libc::c_short,
+    _pad: [u8; 22],
+}
+
+/// Linux tap device
+pub struct Tap {
+    name: String,
+    index: u32,
+    file: File,
+}
+
+impl Tap {
+    /// Creates a tap device, it is removed again when dropped
+    pub fn open(name: &str) -> Fallible<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_NONBLOCK)
+            .open("/dev/net/tun")?;
+
+        let mut req = IfReq {
+            name: [0u8; libc::IFNAMSIZ],
+            flags: IFF_TAP | IFF_NO_PI,
+            _pad: [0u8; 22],
+        };
+        let len = std::cmp::min(name.len(), libc::IFNAMSIZ - 1);
+        req.name[..len].copy_from_slice(&name.as_bytes()[..len]);
+
+        if unsafe { libc::ioctl(file.as_raw_fd(), TUNSETIFF as _, &mut req) } < 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+
+        let c_name = std::ffi::CString::new(&name[..len])?;
+        let index = unsafe { libc::if_nametoindex(c_name.as_ptr()) };
+        if index == 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+
+        Ok(Self {
+            name: name[..len].to_string(),
+            index: index,
+            file: file,
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn index(&self) -> u32 {
+        self.index
+    }
+
+    // Writes a frame coming from the network to the device
+    fn write_frame(&self, frame: &Frame) -> std::io::Result<()> {
+        let mut buf = Vec::with_capacity(ETHERNET_HEADER_LEN + frame.data.len());
+        buf.extend_from_slice(&frame.destination.to_be_bytes()[2..]);
+        buf.extend_from_slice(&frame.source.to_be_bytes()[2..]);
+        buf.extend_from_slice(&frame.ether_type.to_be_bytes());
+        buf.extend_from_slice(frame.data);
+        (&self.file).write_all(&buf)
+    }
+}
+
+/// Returns the name of the tap device for a network
+///
+/// Interface names are limited to 15 characters so the network id is folded
+/// into 40 bits.
+pub fn interface_name(nwid: u64) -> String {
+    format!("zt{:010x}", (nwid ^ (nwid >> 24)) & 0xff_ffff_ffff)
+}
+
+// Returns the names of the tap devices for the networks, networks whose ids
+// fold into the same name get a number appended
+fn interface_names(networks: &[u64]) -> Vec<String> {
+    let mut names: Vec<String> = Vec::new();
+    for nwid in networks {
+        let base = interface_name(*nwid);
+        let mut name = base.clone();
+        let mut n = 1;
+        while names.contains(&name) {
+            name = format!("{}{}", base, n);
+            n += 1;
+        }
+        names.push(name);
+    }
+    names
+}
+
+// Returns the multicast groups (MAC and ADI) the node has to subscribe to so
+// other members can reach it, like MulticastGroup::deriveMulticastGroupForAddressResolution
+fn multicast_groups(config: &VirtualNetworkConfig) -> Vec<(u64, u32)> {
+    let mut groups = Vec::new();
+    if config.broadcast_enabled {
+        groups.push((BROADCAST_MAC, 0));
+    }
+    for address in &config.assigned_addresses {
+        let group = match address.ip() {
+            // ARP requests go to the broadcast MAC with the address as ADI
+            IpAddr::V4(ip) => (BROADCAST_MAC, u32::from(ip)),
+            // Neighbor solicitations go to the solicited-node address
+            IpAddr::V6(ip) => {
+                let o = ip.octets();
+                (mac_to_u64(&[0x33, 0x33, 0xff, o[13], o[14], o[15]]), 0)
+            },
+        };
+        if !groups.contains(&group) {
+            groups.push(group);
+        }
+    }
+    groups
+}
+
+fn mac_to_u64(mac: &[u8]) -> u64 {
+    mac.iter().fold(0u64, |acc, b| (acc << 8) | *b as u64)
+}
+
+// Splits an Ethernet frame read from the device into a ZeroTier frame
+fn parse_frame(nwid: u64, buf: &[u8]) -> Option<Frame<'_>> {
+    if buf.len() < ETHERNET_HEADER_LEN {
+        return None;
+    }
+    Some(Frame {
+        nwid: nwid,
+        destination: mac_to_u64(&buf[0..6]),
+        source: mac_to_u64(&buf[6..12]),
+        ether_type: u16::from_be_bytes([buf[12], buf[13]]),
+        vlan_id: 0,
+        data: &buf[ETHERNET_HEADER_LEN..],
+    })
+}
+
+struct TapFrameHandler(Rc<Tap>);
+
+impl FrameHandler for TapFrameHandler {
+    fn on_frame(&self, frame: &Frame) {
+        if let Err(error) = self.0.write_frame(frame) {
+            println!("unable to write frame to {}: {}", self.0.name(), error);
+        }
+    }
+}
+
+// Addresses and routes currently applied to a tap device
+#[derive(Default)]
+struct Applied {
+    addresses: Vec<IpNetwork>,
+    routes: Vec<Route>,
+}
+
+// Multicast groups of every network
+type Groups = Rc<RefCell<HashMap<u64, Vec<(u64, u32)>>>>;
+
+struct TapConfigHandler {
+    netlink: RefCell<Netlink>,
+    taps: HashMap<u64, Rc<Tap>>,
+    applied: RefCell<HashMap<u64, Applied>>,
+    // Groups to subscribe to, the node can't be called back while it hands
+    // over a config so they are subscribed by `Taps::sync_multicast`
+    groups: Groups,
+}
+
+impl TapConfigHandler {
+    fn apply(&self, tap: &Tap, applied: &mut Applied, addresses: Vec<IpNetwork>, routes: Vec<Route>) -> Fallible<()> {
+        let mut netlink = self.netlink.borrow_mut();
+
+        // Routes can already be gone with their address so removal errors are ignored
+        for route in applied.routes.iter().filter(|r| !routes.contains(r)) {
+            let _ = netlink.del_route(tap.index(), route);
+        }
+        for address in applied.addresses.iter().filter(|a| !addresses.contains(a)) {
+            let _ = netlink.del_address(tap.index(), address);
+        }
+        for address in addresses.iter().filter(|a| !applied.addresses.contains(a)) {
+            netlink.add_address(tap.index(), address)?;
+        }
+        applied.addresses = addresses;
+
+        let mut added = Vec::new();
+        for route in routes.into_iter().filter(|r| !applied.routes.contains(r)) {
+            netlink.add_route(tap.index(), &route)?;
+            added.push(route);
+        }
+        applied.routes.retain(|r| !added.contains(r));
+        applied.routes.append(&mut added);
+
+        Ok(())
+    }
+}
+
+impl NetworkConfigHandler for TapConfigHandler {
+    fn on_network_config(&self, operation: NetworkConfigOperation, config: &VirtualNetworkConfig) -> Fallible<()> {
+        let tap = match self.taps.get(&config.nwid) {
+            Some(tap) => tap,
+            None => return Ok(()),
+        };
+        let mut applied = self.applied.borrow_mut();
+        let applied = applied.entry(config.nwid).or_default();
+
+        match operation {
+            NetworkConfigOperation::Up | NetworkConfigOperation::ConfigUpdate => {
+                self.netlink.borrow_mut().set_link(tap.index(), &config.mac_address(), config.mtu)?;
+
+                // Routes to the networks of assigned addresses are added by the
+                // kernel together with the address
+                let routes = config.routes.iter()
+                    .filter(|r| r.via.is_some() || !config.assigned_addresses.iter().any(|a| {
+                        IpNetwork::new(a.network(), a.prefix()).ok() == Some(r.target)
+                    }))
+                    .cloned()
+                    .collect();
+                self.groups.borrow_mut().insert(config.nwid, multicast_groups(config));
+                self.apply(tap, applied, config.assigned_addresses.clone(), routes)
+            },
+            NetworkConfigOperation::Down | NetworkConfigOperation::Destroy => {
+                self.groups.borrow_mut().insert(config.nwid, Vec::new());
+                self.apply(tap, applied, Vec::new(), Vec::new())
+            },
+        }
+    }
+}
+
+/// Tap devices of joined networks
+pub struct Taps {
+    taps: Vec<(u64, Rc<Tap>)>,
+    groups: Groups,
+    subscribed: RefCell<HashMap<u64, Vec<(u64, u32)>>>,
+}
+
+impl Taps {
+    /// Creates a tap device for every network and registers them with the
+    /// node and poll registry
+    pub fn new(node: &Node, registry: &Registry, networks: &[u64]) -> Fallible<Self> {
+        let mut taps = Vec::new();
+        for (i, (nwid, name)) in networks.iter().zip(interface_names(networks)).enumerate() {
+            let tap = Rc::new(Tap::open(name.as_str())?);
+            registry.register(
+                &mut SourceFd(&tap.file.as_raw_fd()),
+                Token(TAP_TOKEN_BASE + i),
+                Interest::READABLE,
+            )?;
+            node.set_frame_handler(*nwid, Box::new(TapFrameHandler(tap.clone())));
+            println!("created {} for network {:016x}", tap.name(), nwid);
+            taps.push((*nwid, tap));
+        }
+
+        let groups = Groups::default();
+        node.set_network_config_handler(Box::new(TapConfigHandler {
+            netlink: RefCell::new(Netlink::new()?),
+            taps: taps.iter().cloned().collect(),
+            applied: RefCell::new(HashMap::new()),
+            groups: groups.clone(),
+        }));
+
+        Ok(Self {
+            taps: taps,
+            groups: groups,
+            subscribed: RefCell::new(HashMap::new()),
+        })
+    }
+
+    /// Subscribes to the multicast groups of the networks' configs and
+    /// unsubscribes from the ones no longer needed
+    pub fn sync_multicast(&self, node: &Node) {
+        let mut subscribed = self.subscribed.borrow_mut();
+        for (nwid, wanted) in self.groups.borrow_mut().drain() {
+            let current = subscribed.entry(nwid).or_default();
+            for &(mac, adi) in current.iter().filter(|g| !wanted.contains(g)) {
+                if let Err(error) = node.multicast_unsubscribe(nwid, mac, adi) {
+                    println!("unable to unsubscribe from {:012x}/{:08x} on {:016x}: {}", mac, adi, nwid, error);
+                }
+            }
+            for &(mac, adi) in wanted.iter().filter(|g| !current.contains(g)) {
+                if let Err(error) = node.multicast_subscribe(nwid, mac, adi) {
+                    println!("unable to subscribe to {:012x}/{:08x} on {:016x}: {}", mac, adi, nwid, error);
+                }
+            }
+            *current = wanted;
+        }
+    }
+
+    /// Sends all frames waiting on a tap device into the network
+    pub fn process(&self, token: Token, node: &Node) {
+        let (nwid, tap) = match token.0.checked_sub(TAP_TOKEN_BASE).and_then(|i| self.taps.get(i)) {
+            Some(tap) => tap,
+            None => return,
+        };
+
+        let mut buf = [0u8; 10240];
+        loop {
+            let len = match (&tap.file).read(&mut buf) {
+                Ok(len) => len,
+                Err(error) if error.kind() == ErrorKind::WouldBlock => break,
+                Err(error) => {
+                    println!("unable to read from {}: {}", tap.name(), error);
+                    break;
+                },
+            };
+            if let Some(frame) = parse_frame(*nwid, &buf[..len]) {
+                if let Err(error) = node.send_frame(&frame) {
+                    println!("send_frame failed: {}", error);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use std::str::FromStr;
+    use zt::core::{NetworkStatus, NetworkType};
+
+    #[test]
+    fn test_interface_name() {
+        assert_eq!(interface_name(0xba7a59abb06f066b), "zt11ca36addb");
+        assert!(interface_name(u64::MAX).len() <= 15);
+
+        // Both fold into the same 40 bits
+        let names = interface_names(&[0xba7a59abb06f066b, 0xba7a59abb06f066b ^ 0x10000010000, 1]);
+        assert_eq!(names, vec!["zt11ca36addb", "zt11ca36addb1", "zt0000000001"]);
+    }
+
+    #[test]
+    fn test_multicast_groups() -> Fallible<()> {
+        let mut config = test_config()?;
+        config.assigned_addresses.push(IpNetwork::from_str("fd00::1:2:3/88")?);
+
+        assert_eq!(multicast_groups(&config), vec![
+            (0xffffffffffff, 0),
+            (0xffffffffffff, 0x0a931405),
+            (0x3333ff020003, 0),
+        ]);
+        config.broadcast_enabled = false;
+        assert_eq!(multicast_groups(&config).len(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_frame() {
+        let mut buf = vec![0x32, 0xa1, 0xb2, 0xc3, 0xd4, 0xe5, 0x32, 0x11, 0x22, 0x33, 0x44, 0x55, 0x08, 0x00];
+        buf.extend_from_slice(b"payload");
+
+        let frame = parse_frame(1, &buf).unwrap();
+        assert_eq!(frame.destination, 0x32a1b2c3d4e5);
+        assert_eq!(frame.source, 0x321122334455);
+        assert_eq!(frame.ether_type, 0x0800);
+        assert_eq!(frame.data, b"payload");
+        assert!(parse_frame(1, &buf[..10]).is_none());
+    }
+
+    fn test_config() -> Fallible<VirtualNetworkConfig> {
+        Ok(VirtualNetworkConfig {
+            nwid: 1,
+            mac: 0x32a1b2c3d4e5,
+            name: "test".to_string(),
+            status: NetworkStatus::Ok,
+            network_type: NetworkType::Private,
+            mtu: 2800,
+            dhcp: false,
+            bridge: false,
+            broadcast_enabled: true,
+            port_error: 0,
+            revision: 1,
+            assigned_addresses: vec![IpNetwork::from_str("10.147.20.5/24")?],
+            routes: vec![
+                Route { target: IpNetwork::from_str("10.147.20.0/24")?, via: None, flags: 0, metric: 0 },
+                Route {
+                    target: IpNetwork::from_str("10.0.0.0/8")?,
+                    via: Some("10.147.20.1".parse()?),
+                    flags: 0,
+                    metric: 0,
+                },
+            ],
+            multicast_subscriptions: vec![],
+            dns: None,
+        })
+    }
+
+    fn read_sys(name: &str, attr: &str) -> String {
+        std::fs::read_to_string(format!("/sys/class/net/{}/{}", name, attr)).unwrap().trim().to_string()
+    }
+
+    // Needs CAP_NET_ADMIN, run in a network namespace with sysfs remounted:
+    // `unshare -rnm sh -c 'mount -t sysfs sysfs /sys && cargo test -- --ignored'`
+    #[test]
+    #[ignore]
+    fn test_apply_config() -> Fallible<()> {
+        let tap = Rc::new(Tap::open("zttest0")?);
+        let handler = TapConfigHandler {
+            netlink: RefCell::new(Netlink::new()?),
+            taps: vec![(1, tap.clone())].into_iter().collect(),
+            applied: RefCell::new(HashMap::new()),
+            groups: Groups::default(),
+        };
+
+        let mut config = test_config()?;
+        handler.on_network_config(NetworkConfigOperation::Up, &config)?;
+        assert_eq!(handler.groups.borrow()[&1].len(), 2);
+
+        assert_eq!(read_sys("zttest0", "mtu"), "2800");
+        assert_eq!(read_sys("zttest0", "address"), "32:a1:b2:c3:d4:e5");
+        let routes = std::fs::read_to_string("/proc/net/route")?;
+        // Destination 10.0.0.0 via 10.147.20.1 in little endian hex
+        assert!(routes.lines().any(|l| l.starts_with("zttest0\t0000000A\t0114930A")));
+
+        config.routes.truncate(1);
+        handler.on_network_config(NetworkConfigOperation::ConfigUpdate, &config)?;
+        let routes = std::fs::read_to_string("/proc/net/route")?;
+        assert!(!routes.lines().any(|l| l.starts_with("zttest0\t0000000A")));
+
+        handler.on_network_config(NetworkConfigOperation::Destroy, &config)?;
+        let routes = std::fs::read_to_string("/proc/net/route")?;
+        assert!(!routes.lines().any(|l| l.starts_with("zttest0")));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,218 @@
+use serde::{Serialize, Serializer};
+use ipnetwork::Ipv4Network;
+use failure::Fallible;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::path::Path;
+use super::error::AuditError;
+
+/// Decision made by the controller
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Decision {
+    /// Network config was issued in response to a request
+    Issued,
+    /// Network config was pushed to refresh a member's credentials
+    Refreshed,
+    /// Network was not found or the member is not authorized
+    NotFound,
+    /// Network config could not be signed
+    SigningFailed,
+    /// Network config could not be sent
+    SendFailed,
+    /// Member's certificate of membership was revoked
+    Revoked,
+}
+
+/// Record of a single controller decision
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct AuditRecord {
+    /// Time of the decision in milliseconds since epoch
+    pub timestamp: i64,
+    #[serde(serialize_with = "serialize_nwid")]
+    pub nwid: u64,
+    #[serde(serialize_with = "serialize_address")]
+    pub member: u64,
+    /// Physical address the request came from, if it was direct
+    pub source: Option<SocketAddr>,
+    pub decision: Decision,
+    /// Revision of the network config served
+    pub revision: Option<u64>,
+    pub assigned_ips: Vec<Ipv4Network>,
+}
+
+fn serialize_nwid<S: Serializer>(nwid: &u64, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&format!("{:016x}", nwid))
+}
+
+fn serialize_address<S: Serializer>(address: &u64, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&format!("{:010x}", address))
+}
+
+/// Receives a record of every decision made by the controller
+pub trait AuditSink {
+    fn record(&mut self, record: &AuditRecord) -> Fallible<()>;
+}
+
+/// Audit sink appending records to a file as JSON lines
+#[derive(Debug)]
+pub struct FileAuditSink {
+    file: File,
+}
+
+impl FileAuditSink {
+    /// Opens the file for appending, creating it if needed
+    pub fn open<P: AsRef<Path>>(path: P) -> Fallible<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?;
+
+        Ok(Self {
+            file: file,
+        })
+    }
+
+    /// Returns the current size of the file in bytes
+    pub fn len(&self) -> Fallible<u64> {
+        Ok(self.file.metadata()?.len())
+    }
+}
+
+impl AuditSink for FileAuditSink {
+    fn record(&mut self, record: &AuditRecord) -> Fallible<()> {
+        let mut line = serde_json::to_vec(record)?;
+        line.push(b'\n');
+        append_line(&mut self.file, &line)
+    }
+}
+
+// File records are appended to
+trait AppendFile: Write {
+    fn size(&self) -> io::Result<u64>;
+    fn truncate(&mut self, size: u64) -> io::Result<()>;
+}
+
+impl AppendFile for File {
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.metadata()?.len())
+    }
+
+    fn truncate(&mut self, size: u64) -> io::Result<()> {
+        self.set_len(size)
+    }
+}
+
+// Appends a whole line to the file, or nothing at all
+//
+// The file is opened with O_APPEND so every write lands at its end. If only
+// part of the line could be written the file is truncated back to its
+// previous size, the next record starts on a line of its own.
+fn append_line<F: AppendFile>(file: &mut F, line: &[u8]) -> Fallible<()> {
+    let size = file.size()?;
+    if let Err(error) = file.write_all(line) {
+        return match file.truncate(size) {
+            Ok(()) => Err(AuditError::Dropped(error).into()),
+            Err(_) => Err(AuditError::Partial(error).into()),
+        };
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn test_file_audit_sink() -> Fallible<()> {
+        let path = std::env::temp_dir().join(format!("rztc-audit-{}.log", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let mut sink = FileAuditSink::open(&path)?;
+        sink.record(&AuditRecord {
+            timestamp: 1650367222104,
+            nwid: 0xba7a59abb06f066b,
+            member: 0x99e5a948c2,
+            source: Some(SocketAddr::from_str("192.0.2.1:9993")?),
+            decision: Decision::Issued,
+            revision: Some(3),
+            assigned_ips: vec![Ipv4Network::from_str("100.100.0.50/24")?],
+        })?;
+        sink.record(&AuditRecord {
+            timestamp: 1650367222105,
+            nwid: 0xba7a59abb06f066b,
+            member: 0x0000000001,
+            source: None,
+            decision: Decision::NotFound,
+            revision: None,
+            assigned_ips: vec![],
+        })?;
+
+        let expected = concat!(
+            r#"{"timestamp":1650367222104,"nwid":"ba7a59abb06f066b","member":"99e5a948c2","source":"192.0.2.1:9993","decision":"issued","revision":3,"assigned_ips":["100.100.0.50/24"]}"#, "\n",
+            r#"{"timestamp":1650367222105,"nwid":"ba7a59abb06f066b","member":"0000000001","source":null,"decision":"not_found","revision":null,"assigned_ips":[]}"#, "\n",
+        );
+        assert_eq!(std::fs::read_to_string(&path)?, expected);
+        assert_eq!(sink.len()?, expected.len() as u64);
+
+        std::fs::remove_file(&path)?;
+
+        Ok(())
+    }
+
+    // File that runs out of space after a number of bytes
+    struct FullFile {
+        data: Vec<u8>,
+        space: usize,
+    }
+
+    impl Write for FullFile {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let n = buf.len().min(self.space - self.data.len()).min(4);
+            if n == 0 {
+                return Err(io::Error::new(io::ErrorKind::Other, "no space left"));
+            }
+            self.data.extend_from_slice(&buf[..n]);
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl AppendFile for FullFile {
+        fn size(&self) -> io::Result<u64> {
+            Ok(self.data.len() as u64)
+        }
+
+        fn truncate(&mut self, size: u64) -> io::Result<()> {
+            self.data.truncate(size as usize);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_partial_line_is_dropped() -> Fallible<()> {
+        let mut file = FullFile {
+            data: Vec::new(),
+            space: 24,
+        };
+
+        // Written in several short writes
+        append_line(&mut file, b"first record\n")?;
+        assert_eq!(file.data, b"first record\n");
+
+        // Nothing of a line that doesn't fit is kept
+        assert!(append_line(&mut file, b"second record\n").is_err());
+        assert_eq!(file.data, b"first record\n");
+
+        file.space = 64;
+        append_line(&mut file, b"third record\n")?;
+        assert_eq!(file.data, b"first record\nthird record\n");
+
+        Ok(())
+    }
+}
@@ -1,12 +1,13 @@
-use super::Controller;
+use super::{Controller, NetworkRequest};
 use zt_sys::controller::*;
 use crate::dictionary::Dictionary;
 use crate::controller::identity::Identity;
 use ed25519_dalek::{Keypair, PUBLIC_KEY_LENGTH, SECRET_KEY_LENGTH};
+use pnet_sys::sockaddr_to_addr;
 
 macro_rules! to_controller {
     ( $a:expr ) => {
-        unsafe { &mut *($a as *mut Controller) }
+        unsafe { &*($a as *const Controller) }
     };
 }
 
@@ -19,7 +20,7 @@ pub extern "C" fn init_controller(
     kp_len: u64
 ) {
     // Recover the rust native Controller through the user pointer
-    let c: &mut Controller = to_controller!(controller);
+    let c: &Controller = to_controller!(controller);
     // Cast keypair buffer to slice
     let buf = unsafe{ std::slice::from_raw_parts(kp as *const u8, kp_len as usize) };
 
@@ -50,7 +51,7 @@ pub extern "C" fn on_network_request(
     _rztc_controller: *mut RZTC_Controller,
     controller: *mut std::os::raw::c_void,
     nwid: u64,
-    _sockaddr: *const libc::sockaddr_storage,
+    sockaddr: *const libc::sockaddr_storage,
     packet_id: u64,
     identity: u64,
     public_key: *const std::os::raw::c_void,
@@ -58,7 +59,7 @@ pub extern "C" fn on_network_request(
     max_len: u64,
 ) {
     // Recover the rust native Controller through the user pointer
-    let c: &mut Controller = to_controller!(controller);
+    let c: &Controller = to_controller!(controller);
     // Cast metadata_dict to slice
     let buf = unsafe{ std::slice::from_raw_parts(metadata_dict as *const u8, max_len as usize) };
     let index: usize = match buf.iter().position(|x| *x == 0) {
@@ -76,7 +77,20 @@ pub extern "C" fn on_network_request(
         address: identity,
         public: pub_buf.clone(),
     };
+    // The request address is a null address if the request was not direct, in
+    // which case it fails to convert.
+    let source = match sockaddr.is_null() {
+        true => None,
+        false => unsafe {
+            sockaddr_to_addr(&*sockaddr, std::mem::size_of::<libc::sockaddr_storage>()).ok()
+        },
+    };
 
-
-    c.on_request(nwid, packet_id, id, dict);
+    c.on_request(NetworkRequest {
+        nwid: nwid,
+        packet_id: packet_id,
+        identity: id,
+        metadata: Box::new(dict),
+        source: source,
+    });
 }
@@ -34,3 +34,11 @@ pub enum ParseError {
     #[fail(display = "unable to find match")]
     NotFound,
 }
+
+#[derive(Debug, Fail)]
+pub enum AuditError {
+    #[fail(display = "unable to write audit record, dropped it: {}", _0)]
+    Dropped(std::io::Error),
+    #[fail(display = "unable to write audit record, part of it was left in the file: {}", _0)]
+    Partial(std::io::Error),
+}
@@ -65,6 +65,11 @@ impl CertificateOfMembership {
         }
     }
 
+    /// Returns the timestamp qualifier
+    pub fn timestamp(&self) -> u64 {
+        self.qualifiers[0].value
+    }
+
     pub fn serialize(&self) -> Fallible<Vec<u8>> {
         // In ZeroTier CertificateOfMembership is serialized like so:
         // -----------------------------
@@ -1,14 +1,20 @@
 #![allow(non_upper_case_globals)]
 
+mod audit;
 mod callback;
+mod constants;
 mod error;
 mod identity;
 mod membership;
 mod ownership;
 mod networkconfig;
+mod ratelimit;
 pub mod rule;
+mod store;
 
+pub use audit::{AuditSink, AuditRecord, Decision, FileAuditSink};
 use callback::*;
+use constants::*;
 use error::*;
 use zt_sys::controller::*;
 use crate::dictionary::Dictionary;
@@ -16,12 +22,18 @@ use identity::Identity;
 use membership::CertificateOfMembership;
 use ownership::CertificateOfOwnership;
 use networkconfig::{NetworkConfig, NetworkType, TraceLevel, Route};
+use ratelimit::RateLimiter;
+pub use ratelimit::{RequestLimits, RequestStats};
+pub use store::{NetworkStore, MemoryStore, FileStore};
 use num_traits::FromPrimitive;
 use failure::Fallible;
-use std::collections::VecDeque;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::cell::{Cell, RefCell};
 use sha2::Digest;
 use ed25519_dalek::{Keypair, Signer, KEYPAIR_LENGTH};
 use ipnetwork::Ipv4Network;
+use serde::{Serialize, Deserialize};
+use std::net::{IpAddr, SocketAddr};
 use std::time::{SystemTime, UNIX_EPOCH};
 
 #[derive(Debug, Clone)]
@@ -30,9 +42,10 @@ pub struct NetworkRequest {
     pub packet_id: u64,
     pub identity: Identity,
     pub metadata: Box<Dictionary>,
+    pub source: Option<SocketAddr>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Network {
     pub name: String,
     pub id: u32,
@@ -42,17 +55,39 @@ pub struct Network {
     pub broadcast: bool,
     pub multicast_recipient_limit: u64,
     pub mtu: u16,
+    pub credential_time_max_delta: u64,
     pub members: Vec<Member>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Member {
     pub address: u64,
     pub ip: Ipv4Network,
+    /// Membership is not valid before this time (milliseconds since epoch)
+    pub not_before: Option<i64>,
+    /// Membership expires at this time (milliseconds since epoch)
+    pub expires_at: Option<i64>,
+}
+
+impl Member {
+    /// Returns true if the membership is valid at the given time
+    pub fn is_active(&self, now: i64) -> bool {
+        self.not_before.map_or(true, |nb| now >= nb) &&
+            self.expires_at.map_or(true, |exp| now < exp)
+    }
 }
 
 impl Network {
-    fn to_network_config(&self, controller: u64, identity: &Identity) -> Fallible<NetworkConfig> {
+    /// Returns the max delta for credential timestamps, clamped between the
+    /// minimum and maximum ZeroTier allows
+    pub fn credential_time_max_delta(&self) -> u64 {
+        self.credential_time_max_delta.clamp(
+            NETWORKCONFIG_DEFAULT_CREDENTIAL_TIME_MIN_MAX_DELTA,
+            NETWORKCONFIG_DEFAULT_CREDENTIAL_TIME_MAX_MAX_DELTA,
+        )
+    }
+
+    fn to_network_config(&self, controller: u64, identity: &Identity, now: i64) -> Fallible<NetworkConfig> {
         // This little guy will be used to give the user the IP address once CertificateOfOwnership
         // is implemented.
         // TODO!
@@ -61,13 +96,19 @@ impl Network {
             None => return Err(NetworkError::NotFound.into()),
         };
 
-        let now = SystemTime::now().duration_since(UNIX_EPOCH)?;
-        let now: i64 = now.as_millis().try_into()?;
-
         let nwid = (controller << 24) | self.id as u64;
+        let max_delta = self.credential_time_max_delta();
+        // Members accept a certificate of membership as long as its timestamp
+        // is within their own max delta of the timestamp of theirs. Credentials
+        // of an expiring member are backdated, so they stop agreeing with the
+        // ones issued to the others after the membership expired.
+        let credential_timestamp = match member.expires_at {
+            Some(expires_at) => now.min(expires_at - max_delta as i64),
+            None => now,
+        };
 
         let mut coo = CertificateOfOwnership::new(
-            now as u64,
+            credential_timestamp as u64,
             nwid,
             identity,
             1
@@ -78,7 +119,7 @@ impl Network {
             name: self.name.clone(),
             nwid: nwid,
             timestamp: now,
-            credential_time_max_delta: 7200000,
+            credential_time_max_delta: max_delta,
             rev: self.revision,
             multicast_limit: 32,
             network_type: if self.public { NetworkType::Public as u64 } else { NetworkType::Private as u64 },
@@ -99,8 +140,8 @@ impl Network {
                 }
             ],
             com: CertificateOfMembership::new(
-                now as u64,
-                7200000,
+                credential_timestamp as u64,
+                max_delta,
                 nwid,
                 identity,
             ),
@@ -109,66 +150,343 @@ impl Network {
     }
 }
 
+// Keeps track of the last certificate of membership issued to a member
+#[derive(Debug, Clone)]
+struct IssuedCredential {
+    identity: Identity,
+    // Last time the member itself requested a network config
+    last_request: i64,
+    // Time at which the issued certificate of membership stops agreeing with
+    // freshly issued ones
+    expires: i64,
+    max_delta: u64,
+    // False if the certificate was backdated because the membership expires,
+    // refreshing it wouldn't make it last longer
+    renewable: bool,
+}
+
+// Requests received from the node and the limits applied to them
+struct Requests {
+    queue: VecDeque<NetworkRequest>,
+    // Network ID and identity address of every request in the queue
+    queued: HashSet<(u64, u64)>,
+    limits: RequestLimits,
+    address_limiter: RateLimiter<IpAddr>,
+    identity_limiter: RateLimiter<u64>,
+    stats: RequestStats,
+    reported_drops: u64,
+}
+
+impl Requests {
+    fn new(limits: RequestLimits) -> Self {
+        Self {
+            queue: VecDeque::new(),
+            queued: HashSet::new(),
+            address_limiter: RateLimiter::new(limits.address_burst, limits.address_interval, limits.max_tracked),
+            identity_limiter: RateLimiter::new(limits.identity_burst, limits.identity_interval, limits.max_tracked),
+            limits: limits,
+            stats: RequestStats::default(),
+            reported_drops: 0,
+        }
+    }
+
+    // Queues a network config request unless it is a duplicate of one already
+    // queued, its source is over its rate limit or the queue is full.
+    fn enqueue(&mut self, req: NetworkRequest, now: i64) {
+        let key = (req.nwid, req.identity.address);
+        if self.queued.contains(&key) {
+            self.stats.deduplicated += 1;
+            return;
+        }
+
+        // Requests relayed through other nodes have no source address, those
+        // are only limited per identity. Tokens are only used up once both
+        // limiters allow the request.
+        let address_allows = req.source.map_or(true, |s| self.address_limiter.allows(&s.ip(), now));
+        if !address_allows || !self.identity_limiter.allows(&req.identity.address, now) {
+            self.stats.rate_limited += 1;
+            return;
+        }
+        if let Some(source) = req.source {
+            self.address_limiter.check(source.ip(), now);
+        }
+        self.identity_limiter.check(req.identity.address, now);
+
+        if self.queue.len() >= self.limits.queue_size {
+            self.stats.queue_full += 1;
+            return;
+        }
+
+        self.queued.insert(key);
+        self.queue.push_back(req);
+        self.stats.accepted += 1;
+    }
+
+    fn pop(&mut self) -> Option<NetworkRequest> {
+        let req = self.queue.pop_front()?;
+        self.queued.remove(&(req.nwid, req.identity.address));
+        Some(req)
+    }
+}
+
+// Networks and what the controller issued for them
+struct State {
+    store: Box<dyn NetworkStore>,
+    credentials: HashMap<(u64, u64), IssuedCredential>,
+    revoked: HashSet<(u64, u64)>,
+    audit_sink: Option<Box<dyn AuditSink>>,
+}
+
+/// Network controller
+///
+/// ZeroTier calls back into the controller while the node runs, so it is
+/// only ever used through shared references. Requests received through
+/// callbacks are kept apart from the state used to answer them, so neither
+/// is borrowed by a callback while the other is in use.
 pub struct Controller {
-    rztc_controller: *mut RZTC_Controller,
-    networks: Vec<Network>,
-    id: u64,
-    keypair: Option<Keypair>,
-    queue: Box<VecDeque<NetworkRequest>>,
+    rztc_controller: Cell<*mut RZTC_Controller>,
+    id: Cell<u64>,
+    keypair: RefCell<Option<Keypair>>,
+    requests: RefCell<Requests>,
+    state: RefCell<State>,
 }
 
 impl Controller {
     /// Creates an instance of controller
     pub fn new() -> Self {
+        Self::with_store(Box::new(MemoryStore::new()))
+    }
+
+    /// Creates an instance of controller keeping its networks in the given
+    /// store
+    ///
+    /// Several controllers with the same identity sharing a store can answer
+    /// requests for the same networks.
+    pub fn with_store(store: Box<dyn NetworkStore>) -> Self {
         Self {
-            rztc_controller: std::ptr::null_mut(),
-            networks: Vec::new(),
-            id: 0,
-            keypair: None,
-            queue: Box::new(VecDeque::new()),
+            rztc_controller: Cell::new(std::ptr::null_mut()),
+            id: Cell::new(0),
+            keypair: RefCell::new(None),
+            requests: RefCell::new(Requests::new(RequestLimits::default())),
+            state: RefCell::new(State {
+                store: store,
+                credentials: HashMap::new(),
+                revoked: HashSet::new(),
+                audit_sink: None,
+            }),
         }
     }
 
-    /// Gets called when node receives a network config request
-    fn on_request(&mut self, nwid: u64, packet_id: u64, identity: Identity, metadata: Dictionary) {
-        self.queue.push_back(NetworkRequest {
-            nwid: nwid,
-            packet_id: packet_id,
-            identity: identity,
-            metadata: Box::new(metadata),
+    /// Sets the sink receiving a record of every decision the controller makes
+    pub fn set_audit_sink(&mut self, sink: Box<dyn AuditSink>) {
+        self.state.get_mut().audit_sink = Some(sink);
+    }
+
+    fn audit(&self, record: AuditRecord) {
+        if let Some(sink) = &mut self.state.borrow_mut().audit_sink {
+            if let Err(error) = sink.record(&record) {
+                println!("unable to write audit record: {}", error);
+            }
+        }
+    }
+
+    fn audit_request(&self, req: &NetworkRequest, decision: Decision, nc: Option<&NetworkConfig>) {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).expect("unable to get time in millis");
+        self.audit(AuditRecord {
+            timestamp: now.as_millis().try_into().unwrap(),
+            nwid: req.nwid,
+            member: req.identity.address,
+            source: req.source,
+            decision: decision,
+            revision: nc.map(|nc| nc.rev),
+            assigned_ips: nc.and_then(|nc| nc.static_ip).into_iter().collect(),
         });
     }
 
+    /// Sets the limits applied to incoming network config requests
+    pub fn set_request_limits(&mut self, limits: RequestLimits) {
+        let requests = self.requests.get_mut();
+        requests.address_limiter = RateLimiter::new(limits.address_burst, limits.address_interval, limits.max_tracked);
+        requests.identity_limiter = RateLimiter::new(limits.identity_burst, limits.identity_interval, limits.max_tracked);
+        requests.limits = limits;
+    }
+
+    /// Returns counters of accepted and dropped network config requests
+    pub fn request_stats(&self) -> RequestStats {
+        self.requests.borrow().stats.clone()
+    }
+
+    /// Gets called when node receives a network config request
+    fn on_request(&self, req: NetworkRequest) {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).expect("unable to get time in millis");
+        let now: i64 = now.as_millis().try_into().unwrap();
+        self.requests.borrow_mut().enqueue(req, now);
+    }
+
     pub fn process_request(&self, req: &NetworkRequest) {
         let mut nc = match self.get_network_config_for(req.nwid, &req.identity) {
             Ok(nc) => nc,
             Err(error) => {
                 println!("got error trying to find network: {}", error);
+                // Member is no longer authorized, stop refreshing its credentials
+                self.state.borrow_mut().credentials.remove(&(req.nwid, req.identity.address));
                 // Always send NotFound
                 self.send_error(req, NetworkError::NotFound);
+                self.audit_request(req, Decision::NotFound, None);
                 return;
             },
         };
 
 
-        match nc.sign(self.id, self) {
+        match nc.sign(self.id.get(), self) {
             Ok(_) => (),
             Err(error) => {
                 println!("unable to sign network config: {}", error);
+                self.audit_request(req, Decision::SigningFailed, Some(&nc));
                 return;
             },
         };
 
         match self.send_config(req, &nc) {
-            Err(error) => println!("unable to send network config: {}", error),
-            Ok(_) => {},
+            Err(error) => {
+                println!("unable to send network config: {}", error);
+                self.audit_request(req, Decision::SendFailed, Some(&nc));
+            },
+            Ok(_) => {
+                self.track_credential(req, &nc);
+                let decision = if req.packet_id == 0 { Decision::Refreshed } else { Decision::Issued };
+                self.audit_request(req, decision, Some(&nc));
+            },
+        }
+    }
+
+    fn track_credential(&self, req: &NetworkRequest, nc: &NetworkConfig) {
+        let issued = nc.com.timestamp() as i64;
+        let expires = issued + nc.credential_time_max_delta as i64;
+        let renewable = issued == nc.timestamp;
+        let mut state = self.state.borrow_mut();
+        let credential = state.credentials
+            .entry((req.nwid, req.identity.address))
+            .or_insert(IssuedCredential {
+                identity: req.identity.clone(),
+                last_request: nc.timestamp,
+                expires: expires,
+                max_delta: nc.credential_time_max_delta,
+                renewable: renewable,
+            });
+
+        // Configs pushed by the controller itself have no packet ID and should
+        // not keep refreshing credentials for a member that went away.
+        if req.packet_id != 0 {
+            credential.last_request = nc.timestamp;
+        }
+        credential.identity = req.identity.clone();
+        credential.expires = expires;
+        credential.max_delta = nc.credential_time_max_delta;
+        credential.renewable = renewable;
+    }
+
+    // Pushes a fresh network config to members whose certificate of membership
+    // is more than halfway to expiring, as long as the member has requested a
+    // config itself within the last credential lifetime.
+    fn refresh_credentials(&self, now: i64) {
+        let due: Vec<NetworkRequest> = self.state.borrow().credentials
+            .iter()
+            .filter(|(_, c)| {
+                c.renewable &&
+                    now - c.last_request < c.max_delta as i64 &&
+                    c.expires - now < c.max_delta as i64 / 2
+            })
+            .map(|(&(nwid, _), c)| NetworkRequest {
+                nwid: nwid,
+                packet_id: 0,
+                identity: c.identity.clone(),
+                metadata: Box::new(Dictionary::new()),
+                source: None,
+            })
+            .collect();
+
+        for req in due {
+            println!("Refreshing credentials of '{:x}' for network '{:x}'", req.identity.address, req.nwid);
+            self.process_request(&req);
+        }
+
+        // Forget members whose credentials have lapsed
+        self.state.borrow_mut().credentials.retain(|_, c| c.expires > now);
+    }
+
+    // Revokes the certificates of membership of members whose membership has
+    // expired by telling every other member of the network to stop accepting
+    // them.
+    fn revoke_expired_members(&self, now: i64) {
+        let expired = match self.newly_expired_members(now) {
+            Ok(expired) => expired,
+            Err(error) => {
+                println!("unable to read networks from store: {}", error);
+                return;
+            },
+        };
+
+        for (nwid, target, destinations) in expired {
+            println!("Membership of '{:x}' in network '{:x}' has expired, revoking credentials", target, nwid);
+            self.state.borrow_mut().credentials.remove(&(nwid, target));
+            self.audit(AuditRecord {
+                timestamp: now,
+                nwid: nwid,
+                member: target,
+                source: None,
+                decision: Decision::Revoked,
+                revision: None,
+                assigned_ips: Vec::new(),
+            });
+            for dest in destinations {
+                if let Err(error) = self.send_revocation(nwid, target, now, dest) {
+                    println!("unable to send revocation to '{:x}': {}", dest, error);
+                }
+            }
         }
     }
 
+    // Returns the network, address and other members of every member whose
+    // membership has expired since the last call. Members no longer expired,
+    // or no longer members at all, are revoked again once they expire.
+    fn newly_expired_members(&self, now: i64) -> Fallible<Vec<(u64, u64, Vec<u64>)>> {
+        let mut state = self.state.borrow_mut();
+        let state = &mut *state;
+        let mut still_expired = HashSet::new();
+        let mut expired = Vec::new();
+        state.store.for_each_network(&mut |network| {
+            let nwid = (self.id.get() << 24) | network.id as u64;
+            for member in &network.members {
+                if !member.expires_at.map_or(false, |exp| exp <= now) {
+                    continue;
+                }
+                still_expired.insert((nwid, member.address));
+                if state.revoked.insert((nwid, member.address)) {
+                    let destinations = network.members
+                        .iter()
+                        .filter(|m| m.address != member.address)
+                        .map(|m| m.address)
+                        .collect();
+                    expired.push((nwid, member.address, destinations));
+                }
+            }
+        })?;
+        state.revoked.retain(|key| still_expired.contains(key));
+
+        Ok(expired)
+    }
+
+    /// Returns when the certificate of membership last issued to a member
+    /// expires, in milliseconds since epoch
+    pub fn credential_expiry(&self, nwid: u64, address: u64) -> Option<i64> {
+        self.state.borrow().credentials.get(&(nwid, address)).map(|c| c.expires)
+    }
+
     fn send_config(&self, req: &NetworkRequest, nc: &NetworkConfig) -> Fallible<()> {
         unsafe {
             RZTC_Controller_sendConfig(
-                self.rztc_controller,
+                self.rztc_controller.get(),
                 req.nwid,
                 req.packet_id,
                 req.identity.address,
@@ -183,7 +501,7 @@ impl Controller {
     fn send_error(&self, req: &NetworkRequest, ne: NetworkError) -> Fallible<()> {
         unsafe {
             RZTC_Controller_sendError(
-                self.rztc_controller,
+                self.rztc_controller.get(),
                 req.nwid,
                 req.packet_id,
                 req.identity.address,
@@ -196,15 +514,44 @@ impl Controller {
         Ok(())
     }
 
+    fn send_revocation(&self, nwid: u64, target: u64, threshold: i64, dest: u64) -> Fallible<()> {
+        unsafe {
+            RZTC_Controller_sendRevocation(
+                self.rztc_controller.get(),
+                nwid,
+                rand::random::<u32>(),
+                threshold.try_into()?,
+                target,
+                dest
+            );
+        }
+
+        Ok(())
+    }
+
     fn get_network_config_for(&self, nwid: u64, identity: &Identity) -> Fallible<NetworkConfig> {
         let id: u32 = nwid as u32 & 0xffffff;
+        let mut state = self.state.borrow_mut();
 
-        let network = match self.networks.iter().find(|n| n.id == id) {
+        let network = match state.store.network(id)? {
             Some(n) => n,
             None => return Err(NetworkError::NotFound.into()),
         };
 
-        match network.clone().to_network_config(self.id, identity) {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?;
+        let now: i64 = now.as_millis().try_into()?;
+
+        // Members outside of their scheduled membership are treated as if they
+        // were not members at all.
+        match network.members.iter().find(|m| m.address == identity.address) {
+            Some(member) if member.is_active(now) => (),
+            _ => return Err(NetworkError::NotFound.into()),
+        }
+
+        // Timestamp agreed upon with other controllers sharing the store
+        let timestamp = state.store.issue_timestamp(id, now)?;
+
+        match network.to_network_config(self.id.get(), identity, timestamp) {
             Ok(nc) => Ok(nc),
             // Always return NotFound so unauthorized people
             // don't know if they found a network.
@@ -212,17 +559,28 @@ impl Controller {
         }
     }
 
-    fn set_keypair(&mut self, id: u64, keypair: Keypair) {
-        self.id = id;
-        self.keypair = Some(keypair);
+    fn set_keypair(&self, id: u64, keypair: Keypair) {
+        self.id.set(id);
+        *self.keypair.borrow_mut() = Some(keypair);
     }
 
-    pub fn add_network(&mut self, network: Network) {
-        self.networks.push(network);
+    pub fn add_network(&mut self, network: Network) -> Fallible<()> {
+        self.state.get_mut().store.put_network(network)
     }
 
-    pub fn get_network_ids(&self) -> Vec<u64> {
-        self.networks.clone().iter_mut().map(|n| (self.id << 24) & n.id as u64).collect()
+    /// Adds a network unless the store already has it, returns true if it
+    /// was added
+    ///
+    /// Networks in a shared store are kept as they are, they might have
+    /// been changed through another controller.
+    pub fn seed_network(&mut self, network: Network) -> Fallible<bool> {
+        self.state.get_mut().store.seed_network(network)
+    }
+
+    pub fn get_network_ids(&self) -> Fallible<Vec<u64>> {
+        let mut ids = Vec::new();
+        self.state.borrow().store.for_each_network(&mut |n| ids.push((self.id.get() << 24) | n.id as u64))?;
+        Ok(ids)
     }
 }
 
@@ -232,18 +590,17 @@ impl crate::core::Controller for Controller {
             initCallback: Some(init_controller),
             networkRequestCallback: Some(on_network_request),
         };
-        let controller_ptr: *const *mut RZTC_Controller = &self.rztc_controller;
         let controller: *const Controller = self;
 
         let ret: RZTC_ResultCode = unsafe {
             RZTC_Controller_new(
-                controller_ptr as *mut *mut RZTC_Controller,
+                self.rztc_controller.as_ptr(),
                 controller as *mut _,
                 &cbs,
             )
         };
         match ret {
-            0 => Ok(self.rztc_controller as *const _),
+            0 => Ok(self.rztc_controller.get() as *const _),
             _ => match error::FatalError::from_u32(ret) {
                 Some(err) => Err(err.into()),
                 None => Err(error::FatalError::Internal.into()),
@@ -251,18 +608,41 @@ impl crate::core::Controller for Controller {
         }
     }
 
-    fn process_background_tasks(&mut self) -> Fallible<()> {
-        while self.queue.len() > 0 {
-            match self.queue.pop_front() {
-                Some(req) => {
-                    println!("Got network config request from '{:x}' for network '{:x}'", req.identity.address, req.nwid);
-                    self.process_request(&req);
-                },
-                None => println!("no item in queue"),
+    fn process_requests(&self) -> Fallible<()> {
+        // The queue is only borrowed while taking a request out of it, the
+        // node may hand over new requests while one is answered
+        loop {
+            let req = match self.requests.borrow_mut().pop() {
+                Some(req) => req,
+                None => break,
             };
+            println!("Got network config request from '{:x}' for network '{:x}'", req.identity.address, req.nwid);
+            self.process_request(&req);
         }
         Ok(())
     }
+
+    fn process_background_tasks(&self) -> Fallible<()> {
+        self.process_requests()?;
+
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?;
+        let now: i64 = now.as_millis().try_into()?;
+
+        {
+            let mut requests = self.requests.borrow_mut();
+            requests.address_limiter.prune(now);
+            requests.identity_limiter.prune(now);
+            if requests.stats.dropped() > requests.reported_drops {
+                println!("Dropped {} network config requests ({:?})", requests.stats.dropped() - requests.reported_drops, requests.stats);
+                requests.reported_drops = requests.stats.dropped();
+            }
+        }
+
+        self.revoke_expired_members(now);
+        self.refresh_credentials(now);
+
+        Ok(())
+    }
 }
 
 
@@ -278,7 +658,7 @@ impl ZeroTierSigner for Controller {
         // |-------------------|--------|
         // | Ed25519 signature |  hash  |
         // ------------------------------
-        match &self.keypair {
+        match self.keypair.borrow().as_ref() {
             Some(keypair) => {
                 let mut signature = [0u8; 96];
                 let digest = &sha2::Sha512::digest(data)[..32];
@@ -299,3 +679,206 @@ pub trait NetworkConfigProvider {
 pub trait ZeroTierSigner {
     fn sign(&self, data: &[u8]) -> Fallible<[u8; 96]>;
 }
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    fn test_network(credential_time_max_delta: u64) -> Network {
+        Network {
+            name: "test-network".to_string(),
+            id: 0x123456,
+            network: Ipv4Network::from_str("100.100.0.0/24").unwrap(),
+            revision: 1,
+            public: false,
+            broadcast: true,
+            multicast_recipient_limit: 32,
+            mtu: 2800,
+            credential_time_max_delta: credential_time_max_delta,
+            members: vec![
+                Member {
+                    address: 589744919974,
+                    ip: Ipv4Network::from_str("100.100.0.10/24").unwrap(),
+                    not_before: None,
+                    expires_at: None,
+                },
+            ],
+        }
+    }
+
+    fn test_identity() -> Fallible<Identity> {
+        Ok(Identity {
+            address: 589744919974, // 894f8955a6
+            public: hex::decode("2ca7d749ec20a750b6189cf1f51a5f7db67bbed6218cbae506946c01e267cd05d6e4bd580af21231b7edd03eb04a086a43a14cfca67b19a1cc4484e5ad142034")?.try_into().unwrap(),
+        })
+    }
+
+    #[test]
+    fn test_credential_time_max_delta_is_clamped() -> Fallible<()> {
+        assert_eq!(test_network(1000).credential_time_max_delta(), NETWORKCONFIG_DEFAULT_CREDENTIAL_TIME_MIN_MAX_DELTA);
+        assert_eq!(test_network(600000).credential_time_max_delta(), 600000);
+        assert_eq!(test_network(u64::MAX).credential_time_max_delta(), NETWORKCONFIG_DEFAULT_CREDENTIAL_TIME_MAX_MAX_DELTA);
+
+        let nc = test_network(600000).to_network_config(0xaabbccddee, &test_identity()?, 1650367222104)?;
+        assert_eq!(nc.credential_time_max_delta, 600000);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_extended_member_is_revoked_again() -> Fallible<()> {
+        let mut controller = Controller::new();
+        let mut network = test_network(7200000);
+        network.members[0].expires_at = Some(1000);
+        controller.add_network(network.clone())?;
+
+        let expired = controller.newly_expired_members(1000)?;
+        assert_eq!(expired, vec![(0x123456, 589744919974, vec![])]);
+        assert!(controller.newly_expired_members(1500)?.is_empty());
+
+        // Membership extended, then expiring again
+        network.members[0].expires_at = Some(5000);
+        controller.add_network(network)?;
+        assert!(controller.newly_expired_members(2000)?.is_empty());
+        assert!(controller.state.borrow().revoked.is_empty());
+        assert_eq!(controller.newly_expired_members(5000)?, expired);
+
+        Ok(())
+    }
+
+    fn test_request(nwid: u64, address: u64, source: &str) -> NetworkRequest {
+        NetworkRequest {
+            nwid: nwid,
+            packet_id: 1,
+            identity: Identity {
+                address: address,
+                public: [0u8; 64],
+            },
+            metadata: Box::new(Dictionary::new()),
+            source: Some(SocketAddr::from_str(source).unwrap()),
+        }
+    }
+
+    #[test]
+    fn test_request_storm_from_single_address() {
+        let controller = Controller::new();
+
+        // Random network IDs and identities from one address
+        for i in 0..10000u64 {
+            controller.requests.borrow_mut().enqueue(test_request(i * 7919, i, "192.0.2.1:9993"), 0);
+        }
+
+        let limits = RequestLimits::default();
+        assert_eq!(controller.requests.borrow().queue.len(), limits.address_burst as usize);
+        assert_eq!(controller.requests.borrow().stats.accepted, limits.address_burst as u64);
+        assert_eq!(controller.requests.borrow().stats.rate_limited, 10000 - limits.address_burst as u64);
+    }
+
+    #[test]
+    fn test_request_storm_from_single_identity() {
+        let controller = Controller::new();
+
+        // The same identity asking for many networks from many addresses
+        for i in 0..1000u64 {
+            let source = format!("192.0.2.{}:9993", i % 250);
+            controller.requests.borrow_mut().enqueue(test_request(i, 0xaabbccddee, &source), 0);
+        }
+
+        let limits = RequestLimits::default();
+        assert_eq!(controller.requests.borrow().queue.len(), limits.identity_burst as usize);
+        assert_eq!(controller.requests.borrow().stats.rate_limited, 1000 - limits.identity_burst as u64);
+    }
+
+    #[test]
+    fn test_rejected_requests_use_no_tokens() {
+        let controller = Controller::new();
+        let limits = RequestLimits::default();
+
+        // An identity over its limit doesn't use up its address's tokens
+        for i in 0..100u64 {
+            controller.requests.borrow_mut().enqueue(test_request(i, 0xaabbccddee, "192.0.2.1:9993"), 0);
+        }
+        for i in 0..100u64 {
+            controller.requests.borrow_mut().enqueue(test_request(i, i, "192.0.2.1:9993"), 0);
+        }
+
+        assert_eq!(controller.requests.borrow().queue.len(), limits.address_burst as usize);
+        assert_eq!(controller.requests.borrow().stats.rate_limited, 200 - limits.address_burst as u64);
+    }
+
+    #[test]
+    fn test_duplicate_requests_are_dropped() {
+        let controller = Controller::new();
+
+        for _ in 0..100 {
+            controller.requests.borrow_mut().enqueue(test_request(0x123456, 0xaabbccddee, "192.0.2.1:9993"), 0);
+        }
+
+        assert_eq!(controller.requests.borrow().queue.len(), 1);
+        assert_eq!(controller.requests.borrow().stats.deduplicated, 99);
+        assert_eq!(controller.requests.borrow().stats.rate_limited, 0);
+    }
+
+    #[test]
+    fn test_request_queue_is_bounded() {
+        let mut controller = Controller::new();
+        controller.set_request_limits(RequestLimits {
+            queue_size: 100,
+            ..RequestLimits::default()
+        });
+
+        // Distributed storm, every request from a different address and identity
+        for i in 0..10000u64 {
+            let source = format!("10.{}.{}.{}:9993", (i >> 16) & 0xff, (i >> 8) & 0xff, i & 0xff);
+            controller.requests.borrow_mut().enqueue(test_request(i, i, &source), 0);
+        }
+
+        assert_eq!(controller.requests.borrow().queue.len(), 100);
+        assert_eq!(controller.requests.borrow().stats.accepted, 100);
+        assert_eq!(controller.requests.borrow().stats.queue_full, 9900);
+        assert_eq!(controller.requests.borrow().stats.dropped(), 9900);
+    }
+
+    #[test]
+    fn test_member_is_active() -> Fallible<()> {
+        let member = Member {
+            address: 589744919974,
+            ip: Ipv4Network::from_str("100.100.0.10/24")?,
+            not_before: Some(1000),
+            expires_at: Some(2000),
+        };
+
+        assert!(!member.is_active(999));
+        assert!(member.is_active(1000));
+        assert!(member.is_active(1999));
+        assert!(!member.is_active(2000));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_credentials_do_not_outlive_membership() -> Fallible<()> {
+        let now = 1650367222104;
+        let mut network = test_network(7200000);
+        network.members[0].expires_at = Some(now + 60000);
+
+        let nc = network.to_network_config(0xaabbccddee, &test_identity()?, now)?;
+        let max_delta = nc.credential_time_max_delta as i64;
+        assert_eq!(max_delta, 7200000);
+
+        // Certificates issued to others agree with it only until the
+        // membership expires
+        let agrees = |other: i64| (other - nc.com.timestamp() as i64).abs() <= max_delta;
+        assert!(agrees(now));
+        assert!(agrees(now + 59999));
+        assert!(!agrees(now + 60001));
+
+        // Credentials of members that don't expire soon are not backdated
+        network.members[0].expires_at = Some(now + 7200000 * 2);
+        let nc = network.to_network_config(0xaabbccddee, &test_identity()?, now)?;
+        assert_eq!(nc.com.timestamp() as i64, now);
+
+        Ok(())
+    }
+}
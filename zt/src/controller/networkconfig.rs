@@ -1,6 +1,7 @@
 #![allow(dead_code)]
 
 use crate::controller::ZeroTierSigner;
+use crate::controller::constants::NETWORKCONFIG_DEFAULT_CREDENTIAL_TIME_MAX_MAX_DELTA;
 use crate::controller::identity::Identity;
 use crate::controller::membership::CertificateOfMembership;
 use crate::controller::ownership::CertificateOfOwnership;
@@ -11,8 +12,6 @@ use failure::Fallible;
 
 const NETWORKCONFIG_VERSION: u64 = 7;
 
-const NETWORKCONFIG_DEFAULT_CREDENTIAL_TIME_MAX_DELTA: u64 = 7200000;
-
 // Keys used for serializing network config to dictionary
 const DICT_KEY_VERSION: &str = "v";
 const DICT_KEY_NETWORK_ID: &str = "nwid";
@@ -98,7 +97,7 @@ impl NetworkConfig {
             name: name.to_string(),
             nwid: nwid,
             timestamp: now,
-            credential_time_max_delta: NETWORKCONFIG_DEFAULT_CREDENTIAL_TIME_MAX_DELTA,
+            credential_time_max_delta: NETWORKCONFIG_DEFAULT_CREDENTIAL_TIME_MAX_MAX_DELTA,
             network_type: NetworkType::Private as u64,
             multicast_limit: 32,
             rev: rev,
@@ -119,7 +118,7 @@ impl NetworkConfig {
             ],
             com: CertificateOfMembership::new(
                 now as u64,
-                NETWORKCONFIG_DEFAULT_CREDENTIAL_TIME_MAX_DELTA,
+                NETWORKCONFIG_DEFAULT_CREDENTIAL_TIME_MAX_MAX_DELTA,
                 nwid,
                 &issued_to,
             ),
@@ -0,0 +1,263 @@
+use std::collections::{HashMap, VecDeque};
+use std::hash::Hash;
+
+/// Limits applied to incoming network config requests
+#[derive(Debug, Clone)]
+pub struct RequestLimits {
+    /// Maximum number of requests waiting to be processed
+    pub queue_size: usize,
+    /// Number of requests a single source address can make in a burst
+    pub address_burst: u32,
+    /// Milliseconds it takes a source address to earn another request
+    pub address_interval: i64,
+    /// Number of requests a single identity can make in a burst
+    pub identity_burst: u32,
+    /// Milliseconds it takes an identity to earn another request
+    pub identity_interval: i64,
+    /// Maximum number of source addresses or identities tracked at once
+    pub max_tracked: usize,
+}
+
+impl Default for RequestLimits {
+    fn default() -> Self {
+        Self {
+            queue_size: 1024,
+            address_burst: 50,
+            address_interval: 100,
+            identity_burst: 10,
+            identity_interval: 1000,
+            max_tracked: 65536,
+        }
+    }
+}
+
+/// Counters of what happened to incoming network config requests
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct RequestStats {
+    /// Requests put in the queue for processing
+    pub accepted: u64,
+    /// Requests dropped because an identical request was already queued
+    pub deduplicated: u64,
+    /// Requests dropped because the source address or identity was over its limit
+    pub rate_limited: u64,
+    /// Requests dropped because the queue was full
+    pub queue_full: u64,
+}
+
+impl RequestStats {
+    /// Returns the total number of dropped requests
+    pub fn dropped(&self) -> u64 {
+        self.deduplicated + self.rate_limited + self.queue_full
+    }
+}
+
+// Number of tracked keys looked at for one that can be replaced
+const EVICTION_SCAN: usize = 8;
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: u32,
+    last_refill: i64,
+}
+
+/// Token bucket rate limiter keyed by anything hashable
+///
+/// Every key starts with `burst` tokens and earns a new one every `interval`
+/// milliseconds, up to `burst`. Each allowed request uses up a token.
+///
+/// At most `capacity` keys are tracked. A new key only replaces a key whose
+/// bucket is full again, so forgetting it can't give it more tokens. When no
+/// such key is found new keys share a single bucket until one is.
+#[derive(Debug)]
+pub struct RateLimiter<K> {
+    burst: u32,
+    interval: i64,
+    capacity: usize,
+    buckets: HashMap<K, Bucket>,
+    // Tracked keys, oldest first
+    order: VecDeque<K>,
+    // Bucket of the keys that couldn't be tracked
+    untracked: Bucket,
+}
+
+impl<K: Hash + Eq + Clone> RateLimiter<K> {
+    pub fn new(burst: u32, interval: i64, capacity: usize) -> Self {
+        Self {
+            burst: burst,
+            interval: interval.max(1),
+            capacity: capacity.max(1),
+            buckets: HashMap::new(),
+            order: VecDeque::new(),
+            untracked: Bucket {
+                tokens: burst,
+                last_refill: 0,
+            },
+        }
+    }
+
+    /// Returns true if a request for key would be allowed at the given time
+    /// (milliseconds since epoch), without using up a token
+    pub fn allows(&self, key: &K, now: i64) -> bool {
+        match self.buckets.get(key) {
+            Some(bucket) => self.refilled(bucket, now).0 > 0,
+            None if self.buckets.len() < self.capacity || self.evictable(now).is_some() => self.burst > 0,
+            None => self.refilled(&self.untracked, now).0 > 0,
+        }
+    }
+
+    /// Returns true if a request for key is allowed at the given time
+    /// (milliseconds since epoch) and uses up a token
+    pub fn check(&mut self, key: K, now: i64) -> bool {
+        if !self.buckets.contains_key(&key) {
+            if self.buckets.len() >= self.capacity {
+                match self.evictable(now) {
+                    Some(i) => {
+                        let evicted = self.order.remove(i).unwrap();
+                        self.buckets.remove(&evicted);
+                    },
+                    None => {
+                        let (tokens, last_refill) = self.refilled(&self.untracked, now);
+                        return Self::take(&mut self.untracked, tokens, last_refill);
+                    },
+                }
+            }
+            self.order.push_back(key.clone());
+            self.buckets.insert(key.clone(), Bucket {
+                tokens: self.burst,
+                last_refill: now,
+            });
+        }
+
+        let (tokens, last_refill) = self.refilled(&self.buckets[&key], now);
+        Self::take(self.buckets.get_mut(&key).unwrap(), tokens, last_refill)
+    }
+
+    /// Forgets keys that would have a full bucket by now
+    pub fn prune(&mut self, now: i64) {
+        let refill_time = self.interval * self.burst as i64;
+        self.buckets.retain(|_, b| now - b.last_refill < refill_time);
+        let buckets = &self.buckets;
+        self.order.retain(|key| buckets.contains_key(key));
+    }
+
+    // Returns the position in order of one of the oldest keys whose bucket
+    // is full at the given time
+    fn evictable(&self, now: i64) -> Option<usize> {
+        self.order
+            .iter()
+            .take(EVICTION_SCAN)
+            .position(|key| self.refilled(&self.buckets[key], now).0 >= self.burst)
+    }
+
+    // Stores the refilled bucket and uses up a token if there is one
+    fn take(bucket: &mut Bucket, tokens: u32, last_refill: i64) -> bool {
+        bucket.last_refill = last_refill;
+        if tokens == 0 {
+            bucket.tokens = 0;
+            return false;
+        }
+        bucket.tokens = tokens - 1;
+        true
+    }
+
+    // Returns the tokens and time of the last refill of the bucket at the
+    // given time
+    fn refilled(&self, bucket: &Bucket, now: i64) -> (u32, i64) {
+        let earned = (now - bucket.last_refill) / self.interval;
+        if earned <= 0 {
+            return (bucket.tokens, bucket.last_refill);
+        }
+        let tokens = (bucket.tokens as i64 + earned).min(self.burst as i64) as u32;
+        (tokens, bucket.last_refill + earned * self.interval)
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rate_limiter_burst_and_refill() {
+        let mut limiter: RateLimiter<u64> = RateLimiter::new(3, 1000, 16);
+
+        // Burst is allowed
+        assert!(limiter.check(1, 0));
+        assert!(limiter.check(1, 0));
+        assert!(limiter.check(1, 0));
+        assert!(!limiter.allows(&1, 0));
+        assert!(!limiter.check(1, 0));
+
+        // Other keys are unaffected
+        assert!(limiter.allows(&2, 0));
+        assert!(limiter.check(2, 0));
+
+        // One token earned after the interval
+        assert!(!limiter.check(1, 999));
+        assert!(limiter.check(1, 1000));
+        assert!(!limiter.check(1, 1000));
+
+        // Never earns more than the burst
+        assert!(limiter.check(1, 100000));
+        assert!(limiter.check(1, 100000));
+        assert!(limiter.check(1, 100000));
+        assert!(!limiter.check(1, 100000));
+    }
+
+    #[test]
+    fn test_rate_limiter_storm() {
+        let mut limiter: RateLimiter<u64> = RateLimiter::new(10, 100, 1024);
+
+        // 10000 requests from one key within one second
+        let allowed = (0..10000).filter(|i| limiter.check(1, i / 10)).count();
+
+        // Burst plus one token per 100ms
+        assert_eq!(allowed, 10 + 9);
+    }
+
+    #[test]
+    fn test_rate_limiter_capacity_and_prune() {
+        let mut limiter: RateLimiter<u64> = RateLimiter::new(2, 1000, 100);
+
+        // Keys past the capacity share one bucket while no tracked bucket is
+        // full
+        let allowed = (0..10000u64).filter(|key| limiter.check(*key, 0)).count();
+        assert_eq!(allowed, 100 + 2);
+        assert_eq!(limiter.buckets.len(), 100);
+        assert_eq!(limiter.order.len(), 100);
+        assert!(!limiter.allows(&10000, 0));
+
+        // A full bucket makes room for a new key
+        assert!(limiter.allows(&10000, 1000));
+        assert!(limiter.check(10000, 1000));
+        assert!(!limiter.buckets.contains_key(&0));
+        assert!(limiter.buckets.contains_key(&10000));
+        assert_eq!(limiter.buckets.len(), 100);
+
+        // Once their buckets would be full again they are forgotten
+        limiter.prune(1999);
+        assert_eq!(limiter.buckets.len(), 100);
+        limiter.prune(2000);
+        assert_eq!(limiter.buckets.len(), 1);
+        limiter.prune(3000);
+        assert_eq!(limiter.buckets.len(), 0);
+        assert_eq!(limiter.order.len(), 0);
+        assert!(limiter.check(10001, 3000));
+    }
+
+    #[test]
+    fn test_rate_limiter_eviction_keeps_throttled_keys() {
+        let mut limiter: RateLimiter<u64> = RateLimiter::new(2, 1000, 100);
+
+        assert!(limiter.check(1, 0));
+        assert!(limiter.check(1, 0));
+        assert!(!limiter.check(1, 0));
+
+        // Spraying new keys can't push the throttled key out
+        for key in 2..10000u64 {
+            limiter.check(key, 500);
+        }
+        assert!(limiter.buckets.contains_key(&1));
+        assert!(!limiter.allows(&1, 500));
+        assert!(!limiter.check(1, 500));
+    }
+}
@@ -0,0 +1,459 @@
+use super::Network;
+use serde::{Serialize, Deserialize};
+use serde::de::DeserializeOwned;
+use failure::Fallible;
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, ErrorKind};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{channel, RecvTimeoutError, Sender};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+// How often a FileStore syncs with the files in the background
+const SYNC_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Storage for the networks served by a controller
+///
+/// Several controllers running with the same identity can serve the same
+/// networks by sharing a store. Member IPs are part of the networks, so
+/// every controller sharing a store assigns the same ones.
+pub trait NetworkStore {
+    /// Returns every network in the store
+    fn networks(&self) -> Fallible<Vec<Network>>;
+
+    /// Returns the network with the given ID
+    fn network(&self, id: u32) -> Fallible<Option<Network>>;
+
+    /// Calls `f` with every network in the store, without copying them
+    fn for_each_network(&self, f: &mut dyn FnMut(&Network)) -> Fallible<()>;
+
+    /// Adds a network or replaces the network with the same ID
+    ///
+    /// The stored revision is bumped whenever the network changes so every
+    /// controller sharing the store serves the same revision.
+    fn put_network(&mut self, network: Network) -> Fallible<()>;
+
+    /// Adds a network unless the store already has one with the same ID,
+    /// returns true if it was added
+    ///
+    /// Used to seed a shared store without overwriting changes made through
+    /// other controllers.
+    fn seed_network(&mut self, network: Network) -> Fallible<bool>;
+
+    /// Returns the timestamp to use for a network config issued at `now`
+    ///
+    /// It is never earlier than a timestamp previously issued for the same
+    /// network by any controller sharing the store, once they have synced.
+    fn issue_timestamp(&mut self, id: u32, now: i64) -> Fallible<i64>;
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+struct StoreState {
+    networks: BTreeMap<u32, Network>,
+    // Latest network config timestamp issued per network, a FileStore keeps
+    // them in a file of their own
+    #[serde(skip)]
+    timestamps: BTreeMap<u32, i64>,
+}
+
+impl StoreState {
+    fn put_network(&mut self, mut network: Network) {
+        if let Some(current) = self.networks.get(&network.id) {
+            let mut unchanged = current.clone();
+            unchanged.revision = network.revision;
+            network.revision = match unchanged == network {
+                true => current.revision.max(network.revision),
+                false => (current.revision + 1).max(network.revision),
+            };
+        }
+        self.networks.insert(network.id, network);
+    }
+
+    fn seed_network(&mut self, network: Network) -> bool {
+        if self.networks.contains_key(&network.id) {
+            return false;
+        }
+        self.networks.insert(network.id, network);
+        true
+    }
+
+    fn issue_timestamp(&mut self, id: u32, now: i64) -> i64 {
+        let timestamp = self.timestamps.get(&id).map_or(now, |&last| last.max(now));
+        self.timestamps.insert(id, timestamp);
+        timestamp
+    }
+}
+
+// Keeps the latest of each timestamp
+fn merge_timestamps(into: &mut BTreeMap<u32, i64>, from: &BTreeMap<u32, i64>) -> bool {
+    let mut changed = false;
+    for (&id, &timestamp) in from {
+        if into.get(&id).map_or(true, |&last| last < timestamp) {
+            into.insert(id, timestamp);
+            changed = true;
+        }
+    }
+    changed
+}
+
+/// Network store kept in memory, for a controller running on its own
+#[derive(Debug, Default)]
+pub struct MemoryStore(StoreState);
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self(StoreState::default())
+    }
+}
+
+impl NetworkStore for MemoryStore {
+    fn networks(&self) -> Fallible<Vec<Network>> {
+        Ok(self.0.networks.values().cloned().collect())
+    }
+
+    fn network(&self, id: u32) -> Fallible<Option<Network>> {
+        Ok(self.0.networks.get(&id).cloned())
+    }
+
+    fn for_each_network(&self, f: &mut dyn FnMut(&Network)) -> Fallible<()> {
+        self.0.networks.values().for_each(f);
+        Ok(())
+    }
+
+    fn put_network(&mut self, network: Network) -> Fallible<()> {
+        self.0.put_network(network);
+        Ok(())
+    }
+
+    fn seed_network(&mut self, network: Network) -> Fallible<bool> {
+        Ok(self.0.seed_network(network))
+    }
+
+    fn issue_timestamp(&mut self, id: u32, now: i64) -> Fallible<i64> {
+        Ok(self.0.issue_timestamp(id, now))
+    }
+}
+
+/// Network store kept in JSON files that can be shared by several
+/// controllers, e.g. on a shared file system
+///
+/// Requests are answered from memory. A background thread syncs with the
+/// files every second, reading networks changed by other controllers and
+/// merging the timestamps issued since the last sync into a file next to the
+/// store. Files are only changed while holding a lock on a third file and are
+/// written atomically by renaming a temporary file over them.
+///
+/// Adding networks reads and writes the files right away, it can block while
+/// another controller holds the lock.
+pub struct FileStore {
+    files: Arc<StoreFiles>,
+    stop: Option<Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+// Files of a store, along with the state last read from them
+struct StoreFiles {
+    path: PathBuf,
+    state: Mutex<StoreState>,
+    // Timestamps issued since the last sync
+    issued: Mutex<BTreeMap<u32, i64>>,
+}
+
+impl FileStore {
+    /// Opens the store, reading the networks in it
+    pub fn new<P: AsRef<Path>>(path: P) -> Fallible<Self> {
+        let files = Arc::new(StoreFiles {
+            path: path.as_ref().to_path_buf(),
+            state: Mutex::new(StoreState::default()),
+            issued: Mutex::new(BTreeMap::new()),
+        });
+        files.sync()?;
+
+        let (stop, stopped) = channel::<()>();
+        let background = files.clone();
+        let thread = std::thread::spawn(move || loop {
+            // Timestamps are synced one last time when the store is dropped
+            let stopping = !matches!(stopped.recv_timeout(SYNC_INTERVAL), Err(RecvTimeoutError::Timeout));
+            if let Err(error) = background.sync() {
+                println!("unable to sync store {}: {}", background.path.display(), error);
+            }
+            if stopping {
+                break;
+            }
+        });
+
+        Ok(Self {
+            files: files,
+            stop: Some(stop),
+            thread: Some(thread),
+        })
+    }
+
+    /// Syncs with the files right away instead of waiting for the background
+    /// thread
+    pub fn sync(&self) -> Fallible<()> {
+        self.files.sync()
+    }
+
+    // Applies a change to the latest networks while holding the lock
+    fn update<T, F: FnOnce(&mut StoreState) -> T>(&mut self, f: F) -> Fallible<T> {
+        let _lock = StoreLock::acquire(&self.files.sibling(".lock"))?;
+        let mut state: StoreState = read_json(&self.files.path)?.unwrap_or_default();
+        let res = f(&mut state);
+        write_json(&self.files.path, &state)?;
+
+        self.files.state.lock().unwrap().networks = state.networks;
+        Ok(res)
+    }
+}
+
+impl StoreFiles {
+    fn sibling(&self, extension: &str) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(extension);
+        PathBuf::from(path)
+    }
+
+    // Reads the networks and merges the timestamps issued since the last sync
+    // with the ones issued by other controllers
+    fn sync(&self) -> Fallible<()> {
+        let issued = std::mem::take(&mut *self.issued.lock().unwrap());
+        let res = self.sync_issued(&issued);
+        if res.is_err() {
+            // Try again with the next sync
+            merge_timestamps(&mut self.issued.lock().unwrap(), &issued);
+        }
+        res
+    }
+
+    fn sync_issued(&self, issued: &BTreeMap<u32, i64>) -> Fallible<()> {
+        let (stored, timestamps) = {
+            let _lock = StoreLock::acquire(&self.sibling(".lock"))?;
+            let stored: StoreState = read_json(&self.path)?.unwrap_or_default();
+            let timestamps_path = self.sibling(".timestamps");
+            let mut timestamps: BTreeMap<u32, i64> = read_json(&timestamps_path)?.unwrap_or_default();
+            if merge_timestamps(&mut timestamps, issued) {
+                write_json(&timestamps_path, &timestamps)?;
+            }
+            (stored, timestamps)
+        };
+
+        let mut state = self.state.lock().unwrap();
+        state.networks = stored.networks;
+        // Timestamps issued while syncing are kept
+        merge_timestamps(&mut state.timestamps, &timestamps);
+        Ok(())
+    }
+}
+
+impl NetworkStore for FileStore {
+    fn networks(&self) -> Fallible<Vec<Network>> {
+        Ok(self.files.state.lock().unwrap().networks.values().cloned().collect())
+    }
+
+    fn network(&self, id: u32) -> Fallible<Option<Network>> {
+        Ok(self.files.state.lock().unwrap().networks.get(&id).cloned())
+    }
+
+    fn for_each_network(&self, f: &mut dyn FnMut(&Network)) -> Fallible<()> {
+        self.files.state.lock().unwrap().networks.values().for_each(f);
+        Ok(())
+    }
+
+    fn put_network(&mut self, network: Network) -> Fallible<()> {
+        self.update(|state| state.put_network(network))
+    }
+
+    fn seed_network(&mut self, network: Network) -> Fallible<bool> {
+        self.update(|state| state.seed_network(network))
+    }
+
+    fn issue_timestamp(&mut self, id: u32, now: i64) -> Fallible<i64> {
+        let timestamp = self.files.state.lock().unwrap().issue_timestamp(id, now);
+        merge_timestamps(&mut self.files.issued.lock().unwrap(), &BTreeMap::from([(id, timestamp)]));
+        Ok(timestamp)
+    }
+}
+
+impl Drop for FileStore {
+    fn drop(&mut self) {
+        self.stop.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+// Returns None if the file doesn't exist
+fn read_json<T: DeserializeOwned>(path: &Path) -> Fallible<Option<T>> {
+    match fs::read(path) {
+        Ok(buf) => Ok(Some(serde_json::from_slice(&buf)?)),
+        Err(error) if error.kind() == ErrorKind::NotFound => Ok(None),
+        Err(error) => Err(error.into()),
+    }
+}
+
+fn write_json<T: Serialize>(path: &Path, value: &T) -> Fallible<()> {
+    let mut tmp = path.to_path_buf().into_os_string();
+    tmp.push(".tmp");
+    fs::write(&tmp, serde_json::to_vec_pretty(value)?)?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+// Exclusive advisory lock on a file next to the store
+//
+// The lock file is never removed. The lock is released when the file is
+// closed, also when the controller holding it dies.
+struct StoreLock {
+    _file: File,
+}
+
+impl StoreLock {
+    fn acquire(path: &Path) -> Fallible<Self> {
+        let file = OpenOptions::new().write(true).create(true).open(path)?;
+        loop {
+            if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } == 0 {
+                return Ok(Self { _file: file });
+            }
+            let error = io::Error::last_os_error();
+            if error.kind() != ErrorKind::Interrupted {
+                return Err(error.into());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use super::super::Member;
+    use ipnetwork::Ipv4Network;
+    use std::str::FromStr;
+
+    fn test_network(revision: u64, mtu: u16) -> Network {
+        Network {
+            name: "test-network".to_string(),
+            id: 0x123456,
+            network: Ipv4Network::from_str("100.100.0.0/24").unwrap(),
+            revision: revision,
+            public: false,
+            broadcast: true,
+            multicast_recipient_limit: 32,
+            mtu: mtu,
+            credential_time_max_delta: 7200000,
+            members: vec![
+                Member {
+                    address: 0xaabbccddee,
+                    ip: Ipv4Network::from_str("100.100.0.10/24").unwrap(),
+                    not_before: None,
+                    expires_at: None,
+                },
+            ],
+        }
+    }
+
+    fn test_path(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("rztc-store-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir.join("networks.json")
+    }
+
+    #[test]
+    fn test_revision_bumped_on_change() -> Fallible<()> {
+        let mut store = MemoryStore::new();
+
+        store.put_network(test_network(0, 2800))?;
+        assert_eq!(store.networks()?[0].revision, 0);
+
+        // Same network again keeps the revision
+        store.put_network(test_network(0, 2800))?;
+        assert_eq!(store.networks()?[0].revision, 0);
+
+        // A change bumps it
+        store.put_network(test_network(0, 1400))?;
+        assert_eq!(store.networks()?[0].revision, 1);
+
+        // Explicit revisions ahead of the store are respected
+        store.put_network(test_network(10, 1400))?;
+        assert_eq!(store.networks()?[0].revision, 10);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_seed_keeps_stored_network() -> Fallible<()> {
+        let mut store = MemoryStore::new();
+
+        assert!(store.seed_network(test_network(0, 2800))?);
+        store.put_network(test_network(0, 1400))?;
+
+        // A controller started with an older config doesn't undo the change
+        assert!(!store.seed_network(test_network(0, 2800))?);
+        assert_eq!(store.networks()?[0].mtu, 1400);
+        assert_eq!(store.networks()?[0].revision, 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_shared_file_store() -> Fallible<()> {
+        let path = test_path("shared");
+        let mut first = FileStore::new(&path)?;
+        let mut second = FileStore::new(&path)?;
+
+        // Both controllers start up with the same config
+        assert!(first.seed_network(test_network(0, 2800))?);
+        assert!(!second.seed_network(test_network(0, 2800))?);
+        assert_eq!(first.networks()?[0].revision, 0);
+        assert_eq!(second.networks()?[0].revision, 0);
+
+        // A change made through one is seen by the other once it synced
+        first.put_network(test_network(0, 1400))?;
+        second.sync()?;
+        let networks = second.networks()?;
+        assert_eq!(networks[0].mtu, 1400);
+        assert_eq!(networks[0].revision, 1);
+        assert_eq!(second.network(0x123456)?.map(|n| n.mtu), Some(1400));
+        assert!(second.network(0x654321)?.is_none());
+
+        // Timestamps never go backwards between controllers that synced
+        assert_eq!(first.issue_timestamp(0x123456, 2000)?, 2000);
+        first.sync()?;
+        second.sync()?;
+        assert_eq!(second.issue_timestamp(0x123456, 1500)?, 2000);
+        assert_eq!(second.issue_timestamp(0x123456, 2500)?, 2500);
+        second.sync()?;
+        first.sync()?;
+        assert_eq!(first.issue_timestamp(0x123456, 2400)?, 2500);
+
+        // Issued timestamps are written when the store is dropped
+        drop(first);
+        let third = FileStore::new(&path)?;
+        assert_eq!(third.files.state.lock().unwrap().timestamps.get(&0x123456), Some(&2500));
+
+        fs::remove_dir_all(path.parent().unwrap())?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_store_lock_is_exclusive() -> Fallible<()> {
+        let path = test_path("lock").with_extension("lock");
+        let lock = StoreLock::acquire(&path)?;
+
+        // flock locks conflict between open files, also within one process
+        let other = File::open(&path)?;
+        assert_ne!(unsafe { libc::flock(other.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) }, 0);
+        drop(lock);
+        assert_eq!(unsafe { libc::flock(other.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) }, 0);
+
+        fs::remove_dir_all(path.parent().unwrap())?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,70 @@
+use super::*;
+
+/// Creates a node with handlers that are registered before it starts
+///
+/// Events emitted while the node is created (e.g. `Event::Up`) only reach
+/// handlers registered through the builder. Handlers can still be replaced
+/// on the node afterwards.
+pub struct NodeBuilder {
+    state_provider: Box<dyn StateProvider>,
+    phy: Arc<dyn PhyProvider>,
+    event_handler: Option<Box<dyn EventHandler>>,
+    user_message_handler: Option<Box<dyn UserMessageHandler>>,
+    network_config_handler: Option<Box<dyn NetworkConfigHandler>>,
+    path_policy: Option<Box<dyn PathPolicy>>,
+}
+
+impl NodeBuilder {
+    pub fn new(state_provider: Box<dyn StateProvider>, phy: Arc<dyn PhyProvider>) -> Self {
+        Self {
+            state_provider: state_provider,
+            phy: phy,
+            event_handler: None,
+            user_message_handler: None,
+            network_config_handler: None,
+            path_policy: None,
+        }
+    }
+
+    pub fn event_handler(mut self, handler: Box<dyn EventHandler>) -> Self {
+        self.event_handler = Some(handler);
+        self
+    }
+
+    pub fn user_message_handler(mut self, handler: Box<dyn UserMessageHandler>) -> Self {
+        self.user_message_handler = Some(handler);
+        self
+    }
+
+    pub fn network_config_handler(mut self, handler: Box<dyn NetworkConfigHandler>) -> Self {
+        self.network_config_handler = Some(handler);
+        self
+    }
+
+    pub fn path_policy(mut self, policy: Box<dyn PathPolicy>) -> Self {
+        self.path_policy = Some(policy);
+        self
+    }
+
+    /// Creates the node, see `Node::new`
+    pub fn build(self) -> Fallible<Pin<Box<Node>>> {
+        let node = Box::pin(Node {
+            zt_node: Cell::new(std::ptr::null_mut()),
+            online: Cell::new(false),
+            state_provider: self.state_provider,
+            controller: RefCell::new(None),
+            controller_inline: Cell::new(true),
+            event_handler: RefCell::new(self.event_handler),
+            user_message_handler: RefCell::new(self.user_message_handler),
+            network_config_handler: RefCell::new(self.network_config_handler),
+            frame_handlers: RefCell::new(HashMap::new()),
+            path_policy: RefCell::new(self.path_policy),
+            phy: self.phy,
+            commands: handle::Commands::new(),
+            init_error: RefCell::new(None),
+            _pinned: PhantomPinned,
+        });
+        node.init()?;
+        Ok(node)
+    }
+}
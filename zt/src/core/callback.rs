@@ -1,32 +1,185 @@
 use super::*;
 use zt_sys::*;
-use std::ffi::c_void;
-use std::os::raw::{c_int, c_uint};
+use crate::dictionary::Dictionary;
+use std::ffi::{c_void, CStr};
+use std::os::raw::{c_char, c_int, c_uint};
+use std::sync::mpsc::Sender;
 use num_traits::FromPrimitive;
 
-#[derive(Debug, FromPrimitive, PartialEq, Eq)]
+/// Events emitted by the node
+#[derive(Debug, Clone)]
 pub enum Event {
-    Up = ZT_Event_ZT_EVENT_UP as isize,
-    Down = ZT_Event_ZT_EVENT_DOWN as isize,
-    Online = ZT_Event_ZT_EVENT_ONLINE as isize,
-    Offline = ZT_Event_ZT_EVENT_OFFLINE as isize,
+    /// Node has been initialized
+    Up,
+    /// Node is offline, no root servers can be reached
+    Offline,
+    /// Node is online, at least one root server can be reached
+    Online,
+    /// Node is shutting down
+    Down,
+    /// Another node is using the same address as this node, the node can no
+    /// longer operate and should be shut down
+    IdentityCollision,
+    /// Local trace message
+    Trace(String),
+    /// User message received from another node
+    UserMessage(UserMessage),
+    /// Trace sent to this node by another node
+    RemoteTrace(RemoteTrace),
 }
 
-#[derive(Debug, FromPrimitive, PartialEq, Eq)]
+impl Event {
+    // Decodes an event and its payload received from C.
+    //
+    // The payload is only valid for the duration of the callback so everything
+    // is copied into owned values.
+    fn from_raw(event_type: ZT_Event, payload: *const c_void) -> Option<Event> {
+        let event = match event_type {
+            ZT_Event_ZT_EVENT_UP => Event::Up,
+            ZT_Event_ZT_EVENT_OFFLINE => Event::Offline,
+            ZT_Event_ZT_EVENT_ONLINE => Event::Online,
+            ZT_Event_ZT_EVENT_DOWN => Event::Down,
+            ZT_Event_ZT_EVENT_FATAL_ERROR_IDENTITY_COLLISION => Event::IdentityCollision,
+            ZT_Event_ZT_EVENT_TRACE => {
+                if payload.is_null() {
+                    return None;
+                }
+                // unsafe call! We have to trust that ZT_Node passes a null terminated string
+                let msg = unsafe { CStr::from_ptr(payload as *const c_char) };
+                Event::Trace(msg.to_string_lossy().into_owned())
+            },
+            ZT_Event_ZT_EVENT_USER_MESSAGE => {
+                if payload.is_null() {
+                    return None;
+                }
+                let msg = unsafe { &*(payload as *const ZT_UserMessage) };
+                Event::UserMessage(UserMessage {
+                    origin: msg.origin,
+                    type_id: msg.typeId,
+                    data: copy_buffer(msg.data as *const u8, msg.length as usize),
+                })
+            },
+            ZT_Event_ZT_EVENT_REMOTE_TRACE => {
+                if payload.is_null() {
+                    return None;
+                }
+                let trace = unsafe { &*(payload as *const ZT_RemoteTrace) };
+                Event::RemoteTrace(RemoteTrace {
+                    origin: trace.origin,
+                    data: Dictionary::from(copy_buffer(trace.data as *const u8, trace.len as usize)),
+                })
+            },
+            _ => return None,
+        };
+        Some(event)
+    }
+}
+
+// Copies a buffer owned by C into a vector
+fn copy_buffer(data: *const u8, len: usize) -> Vec<u8> {
+    if data.is_null() || len == 0 {
+        return Vec::new();
+    }
+    // unsafe call! We have to trust that ZT_Node reports correct length
+    unsafe { std::slice::from_raw_parts(data, len) }.to_vec()
+}
+
+/// Message sent from another node with `ZT_Node_sendUserMessage`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UserMessage {
+    /// ZeroTier address of the sender
+    pub origin: u64,
+    /// Application defined message type
+    pub type_id: u64,
+    pub data: Vec<u8>,
+}
+
+/// Trace sent by another node, usually to a network controller
+#[derive(Debug, Clone)]
+pub struct RemoteTrace {
+    /// ZeroTier address of the node sending the trace
+    pub origin: u64,
+    /// Trace fields, see `ZT_REMOTE_TRACE_FIELD__*`
+    pub data: Dictionary,
+}
+
+impl RemoteTrace {
+    /// Returns the kind of event being traced
+    pub fn event(&self) -> Option<RemoteTraceEvent> {
+        let event = self.data.get_str("event").ok()?;
+        RemoteTraceEvent::from_u32(event.parse().ok()?)
+    }
+}
+
+/// Kinds of events in a remote trace, see `ZT_REMOTE_TRACE_EVENT__*`
+#[derive(Debug, FromPrimitive, PartialEq, Eq, Clone, Copy)]
+pub enum RemoteTraceEvent {
+    ResettingPathsInScope = 1000,
+    PeerConfirmingUnknownPath = 1001,
+    PeerLearnedNewPath = 1002,
+    PeerRedirected = 1003,
+    PacketMacFailure = 1004,
+    PacketInvalid = 1005,
+    DroppedHello = 1006,
+    OutgoingNetworkFrameDropped = 2000,
+    IncomingNetworkAccessDenied = 2001,
+    IncomingNetworkFrameDropped = 2002,
+    CredentialRejected = 2003,
+    CredentialAccepted = 2004,
+    NetworkConfigRequestSent = 2005,
+    NetworkFilterTrace = 2006,
+}
+
+/// Receives events emitted by the node
+pub trait EventHandler {
+    fn on_event(&self, event: &Event);
+}
+
+// Allows handling events on another thread by registering the sending half
+// of a channel
+impl EventHandler for Sender<Event> {
+    fn on_event(&self, event: &Event) {
+        let _ = self.send(event.clone());
+    }
+}
+
+/// Receives user messages sent to this node by other nodes
+pub trait UserMessageHandler {
+    fn on_user_message(&self, message: &UserMessage);
+}
+
+impl UserMessageHandler for Sender<UserMessage> {
+    fn on_user_message(&self, message: &UserMessage) {
+        let _ = self.send(message.clone());
+    }
+}
+
+#[derive(Debug, FromPrimitive, PartialEq, Eq, Clone, Copy)]
 pub enum StateObject {
+    Null = ZT_StateObjectType_ZT_STATE_OBJECT_NULL as isize,
     PublicIdentity = ZT_StateObjectType_ZT_STATE_OBJECT_IDENTITY_PUBLIC as isize,
     SecretIdentity = ZT_StateObjectType_ZT_STATE_OBJECT_IDENTITY_SECRET as isize,
+    /// The planet (world) definition, id is always zero
+    Planet = ZT_StateObjectType_ZT_STATE_OBJECT_PLANET as isize,
+    /// A moon definition, id[0] is the moon's world id
+    Moon = ZT_StateObjectType_ZT_STATE_OBJECT_MOON as isize,
+    /// Cached peer with its known paths, id[0] is the peer's address
+    Peer = ZT_StateObjectType_ZT_STATE_OBJECT_PEER as isize,
+    /// Config of a joined network, id[0] is the network id
+    NetworkConfig = ZT_StateObjectType_ZT_STATE_OBJECT_NETWORK_CONFIG as isize,
 }
 
-macro_rules! to_node {
-    ( $a:expr ) => {
-        unsafe { &*($a as *const Node) }
-    };
+// Reads the two word object id ZT_Node passes with state objects
+fn state_object_id(id: *const u64) -> [u64; 2] {
+    if id.is_null() {
+        return [0, 0];
+    }
+    unsafe { [*id, *id.add(1)] }
 }
 
-macro_rules! to_mut_node {
+macro_rules! to_node {
     ( $a:expr ) => {
-        unsafe { &mut *($a as *mut Node) }
+        unsafe { &*($a as *const Node) }
     };
 }
 
@@ -37,21 +190,22 @@ pub extern "C" fn state_put_function(
     node: *mut c_void,
     _tptr: *mut c_void,
     object_type: ZT_StateObjectType,
-    _id: *const u64,
+    id: *const u64,
     data: *const c_void,
     len: c_int
 ) {
     // Recover the rust native Node through the user pointer
     let n: &Node = to_node!(node);
+    let id = state_object_id(id);
     // Casting the ZT_StateObjectType from C to rust native enum
     // If it is not recognized we do nothing
     if let Some(state_object) = StateObject::from_u32(object_type) {
         if len < 0 {
-            // call delete_state
+            n.delete_state(state_object, &id);
         } else {
             // unsafe call! We have to trust that ZT_Node reports correct length
             let buf = unsafe{ std::slice::from_raw_parts(data as *const u8, len as usize) };
-            n.set_state(state_object, buf);
+            n.set_state(state_object, &id, buf);
         }
     }
 }
@@ -62,17 +216,18 @@ pub extern "C" fn state_get_function(
     node: *mut c_void,
     _tptr: *mut c_void,
     object_type: ZT_StateObjectType,
-    _id: *const u64,
+    id: *const u64,
     data: *mut c_void,
     len: c_uint
 ) -> c_int {
     // Recover the rust native Node through the user pointer
     let n: &Node = to_node!(node);
+    let id = state_object_id(id);
     // Casting the ZT_StateObjectType from C to rust native enum
     if let Some(state_object) = StateObject::from_u32(object_type) {
         // unsafe call! We have to trust that ZT_Node reports correct length
         let buf = unsafe{ std::slice::from_raw_parts_mut(data as *mut u8, len as usize) };
-        return n.get_state(state_object, buf) as i32;
+        return n.get_state(state_object, &id, buf) as i32;
     }
     -1
 }
@@ -81,7 +236,7 @@ pub extern "C" fn state_get_function(
 pub extern "C" fn wire_packet_send_function(
     _n: *mut ZT_Node,
     node: *mut c_void,
-    tptr: *mut c_void,
+    _tptr: *mut c_void,
     socket: i64,
     address: *const sockaddr_storage,
     data: *const c_void,
@@ -89,29 +244,21 @@ pub extern "C" fn wire_packet_send_function(
     _ttl: c_uint
 ) -> c_int {
     // Recover the rust native Node through the user pointer
-    let n: &mut Node = to_mut_node!(node);
+    let n: &Node = to_node!(node);
     // converting C native sockaddr_storage to rust native SocketAddr
     let addr = unsafe{
-        sockaddr_to_addr(&*address, std::mem::size_of::<sockaddr_storage>()).unwrap()
+        sockaddr_to_addr(&*address, std::mem::size_of::<sockaddr_storage>())
+    };
+    // Address families other than IPv4 and IPv6 can't be sent to
+    let addr = match addr {
+        Ok(addr) => addr,
+        Err(_) => return -1,
     };
     // unsafe call! We have to trust that ZT_Node reports correct length
     let buf = unsafe{ std::slice::from_raw_parts(data as *const u8, len as usize) };
-    // Usually a thread pointer will be passed from C which should contain our PhyWrapper
-    // (see Node.process_wire_packet()). But when the node has a controller, any responses
-    // coming from the controller will not pass any thread pointer.
-    // In those cases we enqueue the packet for processing later. This is more expensive
-    // as the C code owns the buffer memory so if we want to store it for later we have to
-    // copy the entire buffer data. This might not be terribly expensive to do once in a
-    // while (controller responses) but we certainly don't want to do this for every packet.
-    if !tptr.is_null() {
-        // Recover the rust native PhyWrapper through the thread pointer
-        let p: &PhyWrapper = unsafe {
-            &*(tptr as *const PhyWrapper)
-        };
-        !n.on_wire_packet(p.0, buf, socket, addr.clone()) as c_int
-    } else {
-        n.enqueue_wire_packet(buf, socket, addr.clone());
-        0
+    match n.on_wire_packet(buf, socket, addr) {
+        true => 0,
+        false => -1,
     }
 }
 
@@ -121,89 +268,165 @@ pub extern "C" fn event_callback(
     node: *mut c_void,
     _tptr: *mut c_void,
     event_type: ZT_Event,
-    _payload: *const c_void
+    payload: *const c_void
 ) {
     // Recover the rust native Node through the user pointer
     let n: &Node = to_node!(node);
-    if let Some(ev) = Event::from_u32(event_type) {
+    if let Some(ev) = Event::from_raw(event_type, payload) {
         n.on_event(ev);
     } else {
         println!("Uncaught event: {}", event_type);
     }
 }
 
-// TODO: implement
 #[no_mangle]
 pub extern "C" fn virtual_network_config_function(
     _n: *mut ZT_Node,
-    _node: *mut c_void,
+    node: *mut c_void,
     _tptr: *mut c_void,
     _nwid: u64,
     _user: *mut *mut c_void,
-    _op: ZT_VirtualNetworkConfigOperation,
-    _config: *const ZT_VirtualNetworkConfig
+    op: ZT_VirtualNetworkConfigOperation,
+    config: *const ZT_VirtualNetworkConfig
 ) -> c_int {
-    unsafe{
-        println!("nwid: {:x}", _nwid);
-        println!("routeCount: {}", (*_config).routeCount);
-        println!("assignedAddressCount: {}", (*_config).assignedAddressCount);
-        println!("multicastSubscriptionCount: {}", (*_config).multicastSubscriptionCount);
+    // Recover the rust native Node through the user pointer
+    let n: &Node = to_node!(node);
+    if config.is_null() {
+        return -1;
+    }
+    match NetworkConfigOperation::from_u32(op) {
+        Some(operation) => {
+            // The config is only valid for the duration of the callback
+            let config = VirtualNetworkConfig::from(unsafe { &*config });
+            n.on_network_config(operation, config)
+        },
+        None => 0,
     }
-
-    0
 }
 
-// TODO: implement
 #[no_mangle]
 pub extern "C" fn virtual_network_frame_function(
     _n: *mut ZT_Node,
-    _node: *mut c_void,
+    node: *mut c_void,
     _tptr: *mut c_void,
-    _nwid: u64,
+    nwid: u64,
     _user: *mut *mut c_void,
-    _source: u64,
-    _destination: u64,
-    _ether_type: c_uint,
-    _vlan_id: c_uint,
-    _data: *const c_void,
-    _len: c_uint
+    source: u64,
+    destination: u64,
+    ether_type: c_uint,
+    vlan_id: c_uint,
+    data: *const c_void,
+    len: c_uint
 ) {
-    println!("nwid:   {:x}", _nwid);
-    println!("source: {:x}", _source);
-    println!("dest:   {:x}", _destination);
-    println!("vlan:   {:x}", _vlan_id);
-    let buf = unsafe{ std::slice::from_raw_parts(_data as *const u8, _len as usize) };
-    println!("{}", hex::encode(buf));
+    // Recover the rust native Node through the user pointer
+    let n: &Node = to_node!(node);
+    // unsafe call! We have to trust that ZT_Node reports correct length
+    let buf = match data.is_null() {
+        true => &[][..],
+        false => unsafe{ std::slice::from_raw_parts(data as *const u8, len as usize) },
+    };
+    n.on_frame(&Frame {
+        nwid: nwid,
+        source: source,
+        destination: destination,
+        ether_type: ether_type as u16,
+        vlan_id: vlan_id as u16,
+        data: buf,
+    });
 }
 
-// TODO: implement
 #[no_mangle]
 pub extern "C" fn path_check_function(
     _n: *mut ZT_Node,
-    _node: *mut c_void,
+    node: *mut c_void,
     _tptr: *mut c_void,
     ztaddress: u64,
     socket: i64,
     address: *const sockaddr_storage
 ) -> c_int {
+    // Recover the rust native Node through the user pointer
+    let n: &Node = to_node!(node);
     // converting C native sockaddr_storage to rust native SocketAddr
     let addr = unsafe{
-        sockaddr_to_addr(&*address, std::mem::size_of::<sockaddr_storage>()).unwrap()
+        sockaddr_to_addr(&*address, std::mem::size_of::<sockaddr_storage>())
     };
-    println!("path_check: {:x} {} {}", ztaddress, socket, addr);
-    1
+    match addr {
+        Ok(addr) => n.check_path(ztaddress, socket, &addr) as c_int,
+        Err(_) => 1,
+    }
 }
 
-// TODO: implement
 #[no_mangle]
 pub extern "C" fn path_lookup_function(
     _n: *mut ZT_Node,
-    _node: *mut c_void,
+    node: *mut c_void,
     _tptr: *mut c_void,
     ztaddress: u64,
     family: c_int,
-    _address: *mut sockaddr_storage
+    address: *mut sockaddr_storage
 ) -> c_int {
-    println!("path_lookup: {:x} {}", ztaddress, family);
-    0
+    // Recover the rust native Node through the user pointer
+    let n: &Node = to_node!(node);
+    let family = match AddressFamily::from_raw(family) {
+        Some(family) => family,
+        None => return 0,
+    };
+    match n.lookup_path(ztaddress, family) {
+        Some(path) => {
+            // converting rust native SocketAddr to C native sockaddr_storage
+            unsafe { addr_to_sockaddr(path, &mut *address) };
+            1
+        },
+        None => 0,
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_events() {
+        assert!(matches!(Event::from_raw(ZT_Event_ZT_EVENT_ONLINE, std::ptr::null()), Some(Event::Online)));
+        assert!(matches!(
+            Event::from_raw(ZT_Event_ZT_EVENT_FATAL_ERROR_IDENTITY_COLLISION, std::ptr::null()),
+            Some(Event::IdentityCollision)
+        ));
+        assert!(Event::from_raw(ZT_Event_ZT_EVENT_USER_MESSAGE, std::ptr::null()).is_none());
+        assert!(Event::from_raw(100, std::ptr::null()).is_none());
+
+        let trace = b"peer learned path\0";
+        match Event::from_raw(ZT_Event_ZT_EVENT_TRACE, trace.as_ptr() as *const _) {
+            Some(Event::Trace(msg)) => assert_eq!(msg, "peer learned path"),
+            ev => panic!("unexpected event {:?}", ev),
+        }
+
+        let data = [1u8, 2, 3];
+        let msg = ZT_UserMessage {
+            origin: 0x99e5a948c2,
+            typeId: 42,
+            data: data.as_ptr() as *const _,
+            length: data.len() as u32,
+        };
+        match Event::from_raw(ZT_Event_ZT_EVENT_USER_MESSAGE, &msg as *const _ as *const _) {
+            Some(Event::UserMessage(m)) => assert_eq!(m, UserMessage { origin: 0x99e5a948c2, type_id: 42, data: vec![1, 2, 3] }),
+            ev => panic!("unexpected event {:?}", ev),
+        }
+
+        let mut dict = Dictionary::new();
+        dict.set_str("event", "1002");
+        let mut buf = dict.finalize();
+        let trace = ZT_RemoteTrace {
+            origin: 0x99e5a948c2,
+            data: buf.as_mut_ptr() as *mut _,
+            len: buf.len() as u32,
+        };
+        match Event::from_raw(ZT_Event_ZT_EVENT_REMOTE_TRACE, &trace as *const _ as *const _) {
+            Some(Event::RemoteTrace(t)) => {
+                assert_eq!(t.origin, 0x99e5a948c2);
+                assert_eq!(t.event(), Some(RemoteTraceEvent::PeerLearnedNewPath));
+            },
+            ev => panic!("unexpected event {:?}", ev),
+        }
+    }
 }
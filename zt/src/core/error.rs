@@ -19,8 +19,26 @@ pub enum StateError {
     TooLong,
 }
 
-// #[derive(Debug, Fail)]
-// pub enum NetworkError {
-//     #[fail(display = "network not found")]
-//     NotFound,
-// }
+#[derive(Debug, Fail)]
+pub enum NodeError {
+    #[fail(display = "another node is using the same identity")]
+    IdentityCollision,
+    #[fail(display = "unable to send user message to {:010x}", _0)]
+    UserMessageNotSent(u64),
+    #[fail(display = "node is not running")]
+    Stopped,
+    #[fail(display = "node did not reply in time")]
+    Timeout,
+    #[fail(display = "node handles can't be used on the thread driving the node")]
+    DriverThread,
+}
+
+#[derive(Debug, Fail, FromPrimitive)]
+pub enum NetworkError {
+    #[fail(display = "network not found")]
+    NotFound = ZT_ResultCode_ZT_RESULT_ERROR_NETWORK_NOT_FOUND as isize,
+    #[fail(display = "unsupported operation")]
+    UnsupportedOperation = ZT_ResultCode_ZT_RESULT_ERROR_UNSUPPORTED_OPERATION as isize,
+    #[fail(display = "bad parameter")]
+    BadParameter = ZT_ResultCode_ZT_RESULT_ERROR_BAD_PARAMETER as isize,
+}
@@ -0,0 +1,206 @@
+use super::*;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::thread::ThreadId;
+use std::time::Duration;
+
+// How long a handle waits for the driver to reply
+const REPLY_TIMEOUT: Duration = Duration::from_secs(10);
+
+type Waker = Arc<Mutex<Arc<dyn Fn() + Send + Sync>>>;
+
+/// Builds a controller on the thread driving the node
+pub type ControllerFactory = Box<dyn FnOnce() -> Fallible<Box<dyn Controller>> + Send>;
+
+type Reply<T> = Sender<Fallible<T>>;
+
+/// Commands sent by a `NodeHandle` to the thread driving the node
+pub enum Command {
+    Join(u64, Reply<()>),
+    Leave(u64, Reply<()>),
+    Networks(Reply<Vec<VirtualNetworkConfig>>),
+    Peers(Reply<Vec<Peer>>),
+    Status(Reply<NodeStatus>),
+    Orbit(u64, u64, Reply<()>),
+    Deorbit(u64, Reply<()>),
+    RegisterController(ControllerFactory, Reply<()>),
+}
+
+// Both halves of the command channel, kept by the node
+pub(super) struct Commands {
+    tx: Sender<Command>,
+    rx: Receiver<Command>,
+    // Shared with every handle, so handles created before the waker was set
+    // use it too
+    waker: Waker,
+    // The node isn't Send, so it is driven by the thread that created it
+    driver: ThreadId,
+}
+
+impl Commands {
+    pub(super) fn new() -> Self {
+        let (tx, rx) = channel();
+        Self {
+            tx: tx,
+            rx: rx,
+            waker: Arc::new(Mutex::new(Arc::new(|| ()))),
+            driver: std::thread::current().id(),
+        }
+    }
+}
+
+/// Cloneable handle to a node driven by another thread or task
+///
+/// Every call sends a command to the driver and blocks until it replies, so
+/// all calls into ZeroTier are made by the driver one after another. The
+/// driver has to call `Node::process_commands` when it is woken up.
+///
+/// Calls fail with `NodeError::Timeout` if the driver doesn't reply within 10
+/// seconds. Handles can't be used on the driver's thread, e.g. from event or
+/// frame handlers, as the driver would wait for itself.
+#[derive(Clone)]
+pub struct NodeHandle {
+    tx: Sender<Command>,
+    waker: Waker,
+    driver: ThreadId,
+}
+
+impl NodeHandle {
+    fn request<T>(&self, command: impl FnOnce(Reply<T>) -> Command) -> Fallible<T> {
+        if std::thread::current().id() == self.driver {
+            return Err(NodeError::DriverThread.into());
+        }
+        let (tx, rx) = channel();
+        self.tx.send(command(tx)).map_err(|_| NodeError::Stopped)?;
+        let waker = self.waker.lock().unwrap().clone();
+        waker();
+        match rx.recv_timeout(REPLY_TIMEOUT) {
+            Ok(res) => res,
+            Err(RecvTimeoutError::Timeout) => Err(NodeError::Timeout.into()),
+            Err(RecvTimeoutError::Disconnected) => Err(NodeError::Stopped.into()),
+        }
+    }
+
+    pub fn join(&self, nwid: u64) -> Fallible<()> {
+        self.request(|reply| Command::Join(nwid, reply))
+    }
+
+    pub fn leave(&self, nwid: u64) -> Fallible<()> {
+        self.request(|reply| Command::Leave(nwid, reply))
+    }
+
+    pub fn networks(&self) -> Fallible<Vec<VirtualNetworkConfig>> {
+        self.request(Command::Networks)
+    }
+
+    pub fn peers(&self) -> Fallible<Vec<Peer>> {
+        self.request(Command::Peers)
+    }
+
+    pub fn status(&self) -> Fallible<NodeStatus> {
+        self.request(Command::Status)
+    }
+
+    pub fn orbit(&self, world_id: u64, seed: u64) -> Fallible<()> {
+        self.request(|reply| Command::Orbit(world_id, seed, reply))
+    }
+
+    pub fn deorbit(&self, world_id: u64) -> Fallible<()> {
+        self.request(|reply| Command::Deorbit(world_id, reply))
+    }
+
+    /// Registers a controller built by the factory on the driver, controllers
+    /// don't have to be Send
+    pub fn register_controller(&self, factory: ControllerFactory) -> Fallible<()> {
+        self.request(|reply| Command::RegisterController(factory, reply))
+    }
+}
+
+impl Node {
+    /// Returns a handle other threads can use to control the node
+    pub fn handle(&self) -> NodeHandle {
+        NodeHandle {
+            tx: self.commands.tx.clone(),
+            waker: self.commands.waker.clone(),
+            driver: self.commands.driver,
+        }
+    }
+
+    /// Sets the function handles call to wake up the driver after sending a
+    /// command, e.g. `mio::Waker::wake`
+    ///
+    /// Handles created before use it as well.
+    pub fn set_command_waker(&self, waker: Arc<dyn Fn() + Send + Sync>) {
+        *self.commands.waker.lock().unwrap() = waker;
+    }
+
+    /// Runs all commands sent by handles, returns how many were run
+    pub fn process_commands(&self) -> usize {
+        let mut count = 0;
+        while let Ok(command) = self.commands.rx.try_recv() {
+            // Handles which stopped waiting for the reply are ignored
+            let _ = match command {
+                Command::Join(nwid, reply) => reply.send(self.join(nwid)).is_ok(),
+                Command::Leave(nwid, reply) => reply.send(self.leave(nwid)).is_ok(),
+                Command::Networks(reply) => reply.send(self.networks()).is_ok(),
+                Command::Peers(reply) => reply.send(self.peers()).is_ok(),
+                Command::Status(reply) => reply.send(self.status()).is_ok(),
+                Command::Orbit(world_id, seed, reply) => reply.send(self.orbit(world_id, seed)).is_ok(),
+                Command::Deorbit(world_id, reply) => reply.send(self.deorbit(world_id)).is_ok(),
+                Command::RegisterController(factory, reply) => {
+                    let res = factory().and_then(|controller| self.register_controller(controller));
+                    reply.send(res).is_ok()
+                },
+            };
+            count += 1;
+        }
+        count
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn assert_send_sync<T: Send + Sync>() {}
+
+    #[test]
+    fn test_handle() {
+        assert_send_sync::<NodeHandle>();
+
+        let commands = Commands::new();
+        let early = NodeHandle {
+            tx: commands.tx.clone(),
+            waker: commands.waker.clone(),
+            driver: commands.driver,
+        };
+
+        // Handles can't wait for their own thread
+        assert!(matches!(
+            early.status().unwrap_err().downcast_ref::<NodeError>(),
+            Some(NodeError::DriverThread)
+        ));
+
+        // The waker is set after the handle was created
+        let woken = Arc::new(AtomicUsize::new(0));
+        let counter = woken.clone();
+        *commands.waker.lock().unwrap() = Arc::new(move || { counter.fetch_add(1, Ordering::SeqCst); });
+
+        // A driver answering on another thread
+        let rx = commands.rx;
+        let driver = std::thread::spawn(move || {
+            match rx.recv().unwrap() {
+                Command::Join(nwid, reply) => reply.send(Ok(())).map(|_| nwid).unwrap(),
+                _ => panic!("unexpected command"),
+            }
+        });
+        let handle = NodeHandle { driver: driver.thread().id(), ..early };
+        handle.clone().join(0xba7a59abb06f066b).unwrap();
+        assert_eq!(driver.join().unwrap(), 0xba7a59abb06f066b);
+        assert_eq!(woken.load(Ordering::SeqCst), 1);
+
+        // The driver is gone
+        assert!(handle.peers().is_err());
+    }
+}
@@ -1,37 +1,51 @@
 #![allow(non_upper_case_globals)]
 mod error;
 mod callback;
+mod state;
+mod network;
+mod status;
+mod path;
+mod handle;
+mod builder;
 
 pub use error::*;
-pub use callback::StateObject;
+pub use state::DirectoryState;
+pub use network::{
+    VirtualNetworkConfig, NetworkStatus, NetworkType, NetworkConfigOperation, NetworkConfigHandler, Route, Dns,
+    Frame, FrameHandler,
+};
+pub use path::{PathPolicy, AddressFamily};
+pub use handle::{NodeHandle, Command, ControllerFactory};
+pub use builder::NodeBuilder;
+pub use status::{NodeStatus, Peer, PeerPath, PeerRole, WORLD_ID_EARTH};
+pub use callback::{StateObject, Event, EventHandler, UserMessage, UserMessageHandler, RemoteTrace, RemoteTraceEvent};
 use callback::*;
 use zt_sys::*;
 
 use std::time::{SystemTime, UNIX_EPOCH};
 use std::option::Option::Some;
-use std::cell::Cell;
+use std::cell::{Cell, RefCell};
 use std::net::SocketAddr;
+use std::ffi::CStr;
+use ipnetwork::IpNetwork;
 use pnet_sys::{addr_to_sockaddr, sockaddr_to_addr};
 use libc::sockaddr_storage;
 use num_traits::FromPrimitive;
 use failure::Fallible;
-use std::collections::VecDeque;
-
-macro_rules! maybe_init {
-    ( $a:expr ) => {
-        if $a.zt_node.is_null() {
-            $a.init()?;
-        }
-    }
-}
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::marker::PhantomPinned;
+use std::pin::Pin;
 
 macro_rules! handle_res {
     ( $a:expr, $b:expr ) => {
         match $a {
             0 => Ok($b),
-            _ => match FatalError::from_u32($a) {
-                Some(err) => Err(err.into()),
-                None => Err(FatalError::Internal.into()),
+            _ => match (FatalError::from_u32($a), NetworkError::from_u32($a)) {
+                (Some(err), _) => Err(err.into()),
+                (_, Some(err)) => Err(err.into()),
+                _ => Err(FatalError::Internal.into()),
             },
         }
     }
@@ -70,46 +84,47 @@ macro_rules! log_packet {
     };
 }
 
-#[derive(Clone)]
-pub struct WirePacket {
-    socket: i64,
-    address: SocketAddr,
-    buffer: Vec<u8>,
-}
-
+/// ZeroTier node
+///
+/// The node is pinned as its address is handed to ZT_Node, which passes it
+/// back in every callback.
 pub struct Node {
-    zt_node: *mut ZT_Node,
+    zt_node: Cell<*mut ZT_Node>,
     online: Cell<bool>,
     state_provider: Box<dyn StateProvider>,
-    controller: Option<Box<dyn Controller>>,
-    packet_queue: Box<VecDeque<WirePacket>>,
+    controller: RefCell<Option<Rc<dyn Controller>>>,
+    // Whether the controller's work runs along with the node's
+    controller_inline: Cell<bool>,
+    event_handler: RefCell<Option<Box<dyn EventHandler>>>,
+    user_message_handler: RefCell<Option<Box<dyn UserMessageHandler>>>,
+    network_config_handler: RefCell<Option<Box<dyn NetworkConfigHandler>>>,
+    frame_handlers: RefCell<HashMap<u64, Box<dyn FrameHandler>>>,
+    path_policy: RefCell<Option<Box<dyn PathPolicy>>>,
+    phy: Arc<dyn PhyProvider>,
+    commands: handle::Commands,
+    // First state provider failure while ZT_Node_new runs
+    init_error: RefCell<Option<failure::Error>>,
+    _pinned: PhantomPinned,
 }
 
 impl Node {
     /// Creates an instance of node
-    pub fn new(conf_provider: Box<dyn StateProvider>) -> Fallible<Node> {
-        Ok(Self {
-            zt_node: std::ptr::null_mut(),
-            online: Cell::new(false),
-            state_provider: conf_provider,
-            controller: None,
-            packet_queue: Box::new(VecDeque::new()),
-        })
+    ///
+    /// Packets are sent through the phy, including the controller's replies.
+    ///
+    /// The identity is loaded from the state provider, or generated and
+    /// saved if there is none. Failures of the state provider while the node
+    /// is created are returned instead of silently generating a new identity.
+    ///
+    /// Handlers registered afterwards miss the events emitted while the node
+    /// is created (e.g. `Event::Up`), use `NodeBuilder` to register them
+    /// before.
+    pub fn new(state_provider: Box<dyn StateProvider>, phy: Arc<dyn PhyProvider>) -> Fallible<Pin<Box<Node>>> {
+        NodeBuilder::new(state_provider, phy).build()
     }
 
-    // If we create a new instance of Node and pass a pointer to that as the user
-    // pointer to ZT_Node_new before returning the Node to the caller of new()
-    // the pointer value is different from what the caller gets.
-    //
-    // As a result whoever receives the user pointer doesn't have the "same"
-    // Node as the caller of new().
-    //
-    // on_event() needs to mutate self.online and this wasn't working.
-    //
-    // As a workaround I return the Node immediately and initialize it later
-    // once we have the correct pointer value in this init private function
-    // and just call it in every public function if self.zt_node is still
-    // a null pointer.
+    // Creates the ZT_Node, the node must not move anymore as its address is
+    // used as the user pointer
     fn init(&self) -> Fallible<()> {
         // Get current time in millis since epoch
         let now = SystemTime::now().duration_since(UNIX_EPOCH).expect("unable to get time in millis");
@@ -124,90 +139,74 @@ impl Node {
             virtualNetworkConfigFunction: Some(virtual_network_config_function),
             virtualNetworkFrameFunction: Some(virtual_network_frame_function),
             eventCallback: Some(event_callback),
-            pathCheckFunction: None,
-            pathLookupFunction: None,
+            pathCheckFunction: Some(path_check_function),
+            pathLookupFunction: Some(path_lookup_function),
         };
 
-        // Create a double pointer so ZT_Node_new() can set the address of the instance of
-        // ZT_Node. This pointer is used in any subsequent call to ZT_*.
-        let node_mut_ptr: *const *mut ZT_Node = &self.zt_node;
         // Create a user pointer to the node to be used in callbacks.
         let node: *const Node = self;
 
         let ret: ZT_ResultCode = unsafe {
             ZT_Node_new(
-                node_mut_ptr as *mut *mut ZT_Node,
+                // ZT_Node_new() sets the address of the instance of ZT_Node,
+                // it is used in any subsequent call to ZT_*.
+                self.zt_node.as_ptr(),
                 node as *mut _, // Reference to self as user pointer
                 0 as *mut _, // Thread pointer not needed here
                 &cbs,
                 now
             )
         };
-        handle_res!(ret, ())
+        let res: Fallible<()> = handle_res!(ret, ());
+        res?;
+        if let Some(error) = self.init_error.borrow_mut().take() {
+            return Err(error);
+        }
+
+        // ZT_Node only loads the planet by itself, moons have to be orbited
+        // again. Their definitions are then loaded from the state provider.
+        match self.state_provider.list_state(StateObject::Moon) {
+            Ok(moons) => for id in moons {
+                if let Err(error) = self.orbit(id[0], 0) {
+                    println!("unable to orbit stored moon {:016x}: {}", id[0], error);
+                }
+            },
+            Err(error) => println!("unable to list stored moons: {}", error),
+        }
+
+        Ok(())
     }
 
     /// Perform periodic background operations
     ///
     /// Returns next deadline when it should run in milliseconds since epoch
-    pub fn process_background_tasks(&mut self, phy: &dyn PhyProvider) -> Fallible<i64> {
-        maybe_init!(self);
-
+    pub fn process_background_tasks(&self) -> Fallible<i64> {
         let now = SystemTime::now().duration_since(UNIX_EPOCH).expect("unable to get time in millis");
         let now: i64 = now.as_millis().try_into().unwrap();
         let mut next: i64 = 0;
 
-
-        // Calling processBackgroundTasks in C calls a long chain of functions until
-        // eventually calling back into rust in wire_packet_send_function callback.
-        //
-        // Because I don't want Node to own `dyn PhyProvider` I wrap a pointer to
-        // the PhyProvider in a struct and convert that to a raw pointer that I can
-        // pass to C as a thread pointer. This thread pointer is passed down the
-        // entire chain of functions and in wire_packet_send_function we can cast
-        // it back into our wrapper and use the PhyProvider.
-        //
-        // This is of course very unsafe.
-        let phy_wrapper = Box::new(PhyWrapper(phy));
-        let phy_wrapper_ptr: *mut PhyWrapper = Box::into_raw(phy_wrapper);
-
         // Call into C
         let ret = unsafe {
             ZT_Node_processBackgroundTasks(
-                self.zt_node as *mut ZT_Node,
-                phy_wrapper_ptr as *mut _,
+                self.zt_node.get(),
+                std::ptr::null_mut(),
                 now,
                 &mut next
             )
         };
 
-        // Reclaim the PhyProvider wrapper.
-        //
-        // In wire_packet_send_function I'm only dereferncing the wrapper pointer
-        // not taking ownership of the data.
-        unsafe { Box::from_raw(phy_wrapper_ptr) };
-
-        // Process queued packets
-        while self.packet_queue.len() > 0 {
-            match self.packet_queue.pop_front() {
-                Some(packet) => {
-                    self.on_wire_packet(phy, &packet.buffer[..], packet.socket, packet.address);
-                },
-                None => println!("no item in queue"),
-            }
-        }
-
         // Run controller background tasks
-        if let Some(controller) = &mut self.controller {
-            controller.process_background_tasks().unwrap();
+        if let Some(controller) = self.inline_controller() {
+            if let Err(error) = controller.process_background_tasks() {
+                println!("controller background tasks failed: {}", error);
+            }
         }
 
         handle_res!(ret, next)
     }
 
-    pub fn process_wire_packet(&self, phy: &dyn PhyProvider, buf: &[u8], len: usize, addr: &SocketAddr, socket: i64) -> Fallible<i64> {
+    pub fn process_wire_packet(&self, buf: &[u8], len: usize, addr: &SocketAddr, socket: i64) -> Fallible<i64> {
         log_packet!(buf);
-        maybe_init!(self);
-
         // Get current time in millis since epoch
         let now = SystemTime::now().duration_since(UNIX_EPOCH).expect("unable to get time in millis");
         let now: i64 = now.as_millis().try_into().unwrap();
@@ -215,62 +214,33 @@ impl Node {
         // Variable for next deadline
         let mut next: i64 = 0;
 
-        // Calling processWirePacket in C calls a long chain of functions until
-        // eventually (sometimes) calling back into rust in wire_packet_send_function
-        // callback.
-        //
-        // Because I don't want Node to own `dyn PhyProvider` I wrap a pointer to
-        // the PhyProvider in a struct and convert that to a raw pointer that I can
-        // pass to C as a thread pointer. This thread pointer is passed down the
-        // entire chain of functions and in wire_packet_send_function we can cast
-        // it back into our wrapper and use the PhyProvider.
-        //
-        // This is of course very unsafe.
-        let phy_wrapper = Box::new(PhyWrapper(phy));
-        let phy_wrapper_ptr: *mut PhyWrapper = Box::into_raw(phy_wrapper);
+        let mut sockaddr: sockaddr_storage = unsafe { std::mem::zeroed() };
+        addr_to_sockaddr(*addr, &mut sockaddr);
 
         let ret = unsafe {
-            use std::alloc::{alloc, dealloc, Layout};
-
-            let layout = Layout::new::<sockaddr_storage>();
-            let ptr = alloc(layout);
-            let sockaddr = &mut *(ptr as *mut sockaddr_storage);
-
-            addr_to_sockaddr(*addr, sockaddr);
-
-            let res = ZT_Node_processWirePacket(
-                self.zt_node as *mut ZT_Node,
-                phy_wrapper_ptr as *mut _,
+            ZT_Node_processWirePacket(
+                self.zt_node.get(),
+                std::ptr::null_mut(),
                 now,
                 socket,
-                sockaddr,
+                &sockaddr,
                 buf.as_ptr() as *const _,
                 len as u32,
                 &mut next
-            );
-
-            dealloc(ptr, layout);
-            res
+            )
         };
 
-        // Reclaim the PhyProvider wrapper.
-        //
-        // In wire_packet_send_function I'm only dereferncing the wrapper pointer
-        // not taking ownership of the data.
-        unsafe { Box::from_raw(phy_wrapper_ptr) };
+        // Requests in the packet are answered right away instead of at the
+        // next background deadline
+        if let Some(controller) = self.inline_controller() {
+            if let Err(error) = controller.process_requests() {
+                println!("controller failed to process requests: {}", error);
+            }
+        }
 
         handle_res!(ret, next)
     }
 
-    pub fn enqueue_wire_packet(&mut self, buf: &[u8], socket: i64, addr: SocketAddr) {
-        let packet = WirePacket {
-            socket: socket,
-            address: addr,
-            buffer: Vec::from(buf),
-        };
-        self.packet_queue.push_back(packet);
-    }
-
     pub fn add_local_interface_address(&self, address: &SocketAddr) -> Fallible<()> {
         unsafe {
             use std::alloc::{alloc, dealloc, Layout};
@@ -281,7 +251,7 @@ impl Node {
 
             addr_to_sockaddr(*address, sockaddr);
 
-            let res = ZT_Node_addLocalInterfaceAddress(self.zt_node as *mut ZT_Node, sockaddr);
+            let res = ZT_Node_addLocalInterfaceAddress(self.zt_node.get(), sockaddr);
 
             dealloc(ptr, layout);
             match res != 0 {
@@ -293,7 +263,264 @@ impl Node {
 
     pub fn clear_local_interface_addresses(&self) {
         unsafe {
-            ZT_Node_clearLocalInterfaceAddresses(self.zt_node as *mut ZT_Node);
+            ZT_Node_clearLocalInterfaceAddresses(self.zt_node.get());
+        }
+    }
+
+    /// Joins a virtual network
+    ///
+    /// Changes to the network config are reported to the registered
+    /// `NetworkConfigHandler`.
+    pub fn join(&self, nwid: u64) -> Fallible<()> {
+        let ret = unsafe {
+            ZT_Node_join(self.zt_node.get(), nwid, std::ptr::null_mut(), std::ptr::null_mut())
+        };
+        handle_res!(ret, ())
+    }
+
+    /// Leaves a virtual network
+    pub fn leave(&self, nwid: u64) -> Fallible<()> {
+        let ret = unsafe {
+            ZT_Node_leave(self.zt_node.get(), nwid, std::ptr::null_mut(), std::ptr::null_mut())
+        };
+        handle_res!(ret, ())
+    }
+
+    /// Returns configs of all joined networks
+    pub fn networks(&self) -> Fallible<Vec<VirtualNetworkConfig>> {
+        let list = unsafe { ZT_Node_networks(self.zt_node.get()) };
+        if list.is_null() {
+            return Err(FatalError::OutOfMemory.into());
+        }
+
+        // Copy everything out before the list is freed
+        let networks = unsafe {
+            let count = (*list).networkCount as usize;
+            let networks = match count {
+                0 => Vec::new(),
+                _ => std::slice::from_raw_parts((*list).networks, count)
+                    .iter()
+                    .map(VirtualNetworkConfig::from)
+                    .collect(),
+            };
+            ZT_Node_freeQueryResult(self.zt_node.get(), list as *mut _);
+            networks
+        };
+        Ok(networks)
+    }
+
+    /// Returns config of a joined network, None if the network is not joined
+    pub fn network_config(&self, nwid: u64) -> Fallible<Option<VirtualNetworkConfig>> {
+        let conf = unsafe { ZT_Node_networkConfig(self.zt_node.get(), nwid) };
+        if conf.is_null() {
+            return Ok(None);
+        }
+
+        let res = unsafe {
+            let res = VirtualNetworkConfig::from(&*conf);
+            ZT_Node_freeQueryResult(self.zt_node.get(), conf as *mut _);
+            res
+        };
+        Ok(Some(res))
+    }
+
+    /// Subscribes to a multicast group (MAC and ADI) on a joined network
+    pub fn multicast_subscribe(&self, nwid: u64, group: u64, adi: u32) -> Fallible<()> {
+        let ret = unsafe {
+            ZT_Node_multicastSubscribe(self.zt_node.get(), std::ptr::null_mut(), nwid, group, adi as _)
+        };
+        handle_res!(ret, ())
+    }
+
+    /// Unsubscribes from a multicast group on a joined network
+    pub fn multicast_unsubscribe(&self, nwid: u64, group: u64, adi: u32) -> Fallible<()> {
+        let ret = unsafe {
+            ZT_Node_multicastUnsubscribe(self.zt_node.get(), nwid, group, adi as _)
+        };
+        handle_res!(ret, ())
+    }
+
+    /// Registers a handler receiving config changes of joined networks
+    pub fn set_network_config_handler(&self, handler: Box<dyn NetworkConfigHandler>) {
+        *self.network_config_handler.borrow_mut() = Some(handler);
+    }
+
+    // Gets called from C (through a callback wrapper) when the config of a
+    // joined network changes
+    fn on_network_config(&self, operation: NetworkConfigOperation, config: VirtualNetworkConfig) -> i32 {
+        if let Some(handler) = self.network_config_handler.borrow().as_ref() {
+            if let Err(error) = handler.on_network_config(operation, &config) {
+                println!("unable to apply config of network {:016x}: {}", config.nwid, error);
+                return -1;
+            }
+        }
+        0
+    }
+
+    /// Registers a handler receiving frames of a network
+    ///
+    /// Frames of networks without a handler are dropped.
+    pub fn set_frame_handler(&self, nwid: u64, handler: Box<dyn FrameHandler>) {
+        self.frame_handlers.borrow_mut().insert(nwid, handler);
+    }
+
+    pub fn remove_frame_handler(&self, nwid: u64) {
+        self.frame_handlers.borrow_mut().remove(&nwid);
+    }
+
+    /// Sends an Ethernet frame from this node to a joined network
+    ///
+    /// Returns next deadline when background tasks should run in milliseconds
+    /// since epoch
+    pub fn send_frame(&self, frame: &Frame) -> Fallible<i64> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).expect("unable to get time in millis");
+        let now: i64 = now.as_millis().try_into().unwrap();
+        let mut next: i64 = 0;
+
+        let ret = unsafe {
+            ZT_Node_processVirtualNetworkFrame(
+                self.zt_node.get(),
+                std::ptr::null_mut(),
+                now,
+                frame.nwid,
+                frame.source,
+                frame.destination,
+                frame.ether_type as u32,
+                frame.vlan_id as u32,
+                frame.data.as_ptr() as *const _,
+                frame.data.len() as u32,
+                &mut next
+            )
+        };
+
+        handle_res!(ret, next)
+    }
+
+    // Gets called from C (through a callback wrapper) when a frame arrives
+    // on a joined network
+    fn on_frame(&self, frame: &Frame) {
+        if let Some(handler) = self.frame_handlers.borrow().get(&frame.nwid) {
+            handler.on_frame(frame);
+        }
+    }
+
+    /// Returns the ZeroTier address of the node
+    pub fn address(&self) -> Fallible<u64> {
+        Ok(unsafe { ZT_Node_address(self.zt_node.get()) })
+    }
+
+    pub fn status(&self) -> Fallible<NodeStatus> {
+        let mut status: ZT_NodeStatus = unsafe { std::mem::zeroed() };
+        unsafe { ZT_Node_status(self.zt_node.get(), &mut status) };
+
+        // The identity strings are owned by the node
+        let public_identity = match status.publicIdentity.is_null() {
+            true => String::new(),
+            false => unsafe { CStr::from_ptr(status.publicIdentity) }.to_string_lossy().into_owned(),
+        };
+
+        Ok(NodeStatus {
+            address: status.address,
+            public_identity: public_identity,
+            online: status.online != 0,
+            world_id: self.world_id(),
+        })
+    }
+
+    /// Returns all peers the node knows about
+    pub fn peers(&self) -> Fallible<Vec<Peer>> {
+        let list = unsafe { ZT_Node_peers(self.zt_node.get()) };
+        if list.is_null() {
+            return Err(FatalError::OutOfMemory.into());
+        }
+
+        // Copy everything out before the list is freed
+        let peers = unsafe {
+            let count = (*list).peerCount as usize;
+            let peers = match count {
+                0 => Vec::new(),
+                _ => std::slice::from_raw_parts((*list).peers, count)
+                    .iter()
+                    .map(Peer::from)
+                    .collect(),
+            };
+            ZT_Node_freeQueryResult(self.zt_node.get(), list as *mut _);
+            peers
+        };
+        Ok(peers)
+    }
+
+    // The world id isn't part of ZT_NodeStatus. A custom planet is always
+    // stored as a state object so it is read from there.
+    fn world_id(&self) -> u64 {
+        match self.state_provider.get_state(StateObject::Planet, &[0, 0]) {
+            Ok(planet) if planet.len() >= 9 => u64::from_be_bytes(planet[1..9].try_into().unwrap()),
+            _ => WORLD_ID_EARTH,
+        }
+    }
+
+    /// Starts orbiting a moon
+    ///
+    /// The seed is the address of one of the moon's roots, it is used to fetch
+    /// the moon definition if it isn't stored yet. Moon definitions are kept
+    /// through the state provider.
+    pub fn orbit(&self, world_id: u64, seed: u64) -> Fallible<()> {
+        let ret = unsafe {
+            ZT_Node_orbit(self.zt_node.get(), std::ptr::null_mut(), world_id, seed)
+        };
+        handle_res!(ret, ())
+    }
+
+    /// Stops orbiting a moon and removes its stored definition
+    pub fn deorbit(&self, world_id: u64) -> Fallible<()> {
+        let ret = unsafe {
+            ZT_Node_deorbit(self.zt_node.get(), std::ptr::null_mut(), world_id)
+        };
+        handle_res!(ret, ())
+    }
+
+    /// Sets MTU and trusted path id of a physical network
+    ///
+    /// Packets on trusted paths are not encrypted or authenticated, only use
+    /// them on networks where every host is trusted. An MTU of 0 means the
+    /// default and a trusted path id of 0 means the path isn't trusted.
+    pub fn set_physical_path_config(&self, network: &IpNetwork, mtu: u32, trusted_path_id: u64) -> Fallible<()> {
+        let config = ZT_PhysicalPathConfiguration {
+            trustedPathId: trusted_path_id,
+            mtu: mtu as i32,
+        };
+        // ZeroTier keeps the netmask bits of a network in the port
+        let mut sockaddr: sockaddr_storage = unsafe { std::mem::zeroed() };
+        addr_to_sockaddr(SocketAddr::new(network.network(), network.prefix() as u16), &mut sockaddr);
+
+        let ret = unsafe {
+            ZT_Node_setPhysicalPathConfiguration(self.zt_node.get(), &sockaddr, &config)
+        };
+        handle_res!(ret, ())
+    }
+
+    /// Registers a policy deciding which physical paths may be used
+    pub fn set_path_policy(&self, policy: Box<dyn PathPolicy>) {
+        *self.path_policy.borrow_mut() = Some(policy);
+    }
+
+    // Gets called from C (through a callback wrapper) before the node uses a
+    // physical path to reach a peer
+    fn check_path(&self, address: u64, socket: i64, path: &SocketAddr) -> bool {
+        match self.path_policy.borrow().as_ref() {
+            Some(policy) => policy.check_path(address, socket, path),
+            None => true,
+        }
+    }
+
+    // Gets called from C (through a callback wrapper) when the node looks for
+    // a physical address of a peer
+    fn lookup_path(&self, address: u64, family: AddressFamily) -> Option<SocketAddr> {
+        let path = self.path_policy.borrow().as_ref()?.lookup_path(address, family)?;
+        // Don't trust the policy to honor the family
+        match family.matches(&path) {
+            true => Some(path),
+            false => None,
         }
     }
 
@@ -309,81 +536,198 @@ impl Node {
         format!("{}.{}.{}", major, minor, patch)
     }
 
+    /// Registers a handler receiving every event emitted by the node
+    ///
+    /// A `std::sync::mpsc::Sender<Event>` can be registered to receive the
+    /// events on a channel.
+    pub fn set_event_handler(&self, handler: Box<dyn EventHandler>) {
+        *self.event_handler.borrow_mut() = Some(handler);
+    }
+
+    /// Registers a handler receiving user messages sent to this node
+    ///
+    /// User messages are still emitted as events as well.
+    pub fn set_user_message_handler(&self, handler: Box<dyn UserMessageHandler>) {
+        *self.user_message_handler.borrow_mut() = Some(handler);
+    }
+
+    /// Sends a user message (VERB_USER_MESSAGE) to another node
+    ///
+    /// The message is authenticated and encrypted like any other packet
+    /// between the nodes. The type id is application defined, ids below 1000
+    /// are reserved by ZeroTier.
+    pub fn send_user_message(&self, dest: u64, type_id: u64, payload: &[u8]) -> Fallible<()> {
+        let sent = unsafe {
+            ZT_Node_sendUserMessage(
+                self.zt_node.get(),
+                std::ptr::null_mut(),
+                dest,
+                type_id,
+                payload.as_ptr() as *const _,
+                payload.len() as u32,
+            )
+        };
+
+        match sent {
+            0 => Err(NodeError::UserMessageNotSent(dest).into()),
+            _ => Ok(()),
+        }
+    }
+
     // Gets called from C (through a callback wrapper) when an event occurs
     fn on_event(&self, event: Event) {
-        match event {
+        match &event {
             Event::Online => self.online.set(true),
             Event::Offline => self.online.set(false),
+            Event::UserMessage(message) => if let Some(handler) = self.user_message_handler.borrow().as_ref() {
+                handler.on_user_message(message);
+            },
             _ => (),
         }
+        if let Some(handler) = self.event_handler.borrow().as_ref() {
+            handler.on_event(&event);
+        }
     }
 
     // Gets called from C (through a callback wrapper) when a packet should be
-    // sent to a socket
-    fn on_wire_packet(&self, phy: &dyn PhyProvider, buf: &[u8], socket: i64, addr: SocketAddr) -> bool {
+    // sent to a socket, returns true if it was sent
+    fn on_wire_packet(&self, buf: &[u8], socket: i64, addr: SocketAddr) -> bool {
         log_packet!(buf);
-        if addr.is_ipv4() {
-            match socket {
-                -1 => phy.send_all(&addr, buf),
-                _ => phy.send(&addr, socket, buf),
-            };
+        match socket {
+            -1 => self.phy.send_all(&addr, buf) > 0,
+            _ => self.phy.send(&addr, socket, buf) > 0,
         }
-        false
     }
 
     // Gets called from C (through a callback wrapper) when the node wants to
     // save state
-    fn set_state(&self, object_type: StateObject, buf: &[u8]) {
-        let _ret = self.state_provider.set_state(object_type, buf);
+    fn set_state(&self, object_type: StateObject, id: &[u64; 2], buf: &[u8]) {
+        // Don't replace state that couldn't be read, e.g. with a newly
+        // generated identity
+        if self.init_error.borrow().is_some() {
+            return;
+        }
+        if let Err(error) = self.state_provider.set_state(object_type, id, buf) {
+            println!("unable to save {:?} state: {}", object_type, error);
+            self.set_init_error(error);
+        }
+    }
+
+    // Remembers the first state provider failure while the node is created
+    fn set_init_error(&self, error: failure::Error) {
+        if self.zt_node.get().is_null() {
+            self.init_error.borrow_mut().get_or_insert(error);
+        }
     }
 
     // Gets called from C (through a callback wrapper) when the node wants to
-    // get state
-    fn get_state(&self, object_type: StateObject, buf: &mut [u8]) -> i32 {
-        if let Ok(value) = self.state_provider.get_state(object_type) {
-            let len = value.len();
-            buf[..len].copy_from_slice(&value);
-            return len as i32;
+    // delete state
+    fn delete_state(&self, object_type: StateObject, id: &[u64; 2]) {
+        if let Err(error) = self.state_provider.delete_state(object_type, id) {
+            println!("unable to delete {:?} state: {}", object_type, error);
         }
-        -1
     }
 
-    pub fn register_controller(&mut self, controller: Box<dyn Controller>) -> Fallible<()> {
-        maybe_init!(self);
+    // Gets called from C (through a callback wrapper) when the node wants to
+    // get state
+    fn get_state(&self, object_type: StateObject, id: &[u64; 2], buf: &mut [u8]) -> i32 {
+        match self.state_provider.get_state(object_type, id) {
+            Ok(value) => {
+                let len = value.len();
+                // Objects that don't fit in the buffer are treated as missing
+                if len > buf.len() {
+                    return -1;
+                }
+                buf[..len].copy_from_slice(&value);
+                len as i32
+            },
+            Err(error) => {
+                if !matches!(error.downcast_ref::<StateError>(), Some(StateError::NotFound)) {
+                    println!("unable to load {:?} state: {}", object_type, error);
+                    self.set_init_error(error);
+                }
+                -1
+            },
+        }
+    }
 
+    pub fn register_controller(&self, controller: Box<dyn Controller>) -> Fallible<()> {
         let ctrl_ptr = controller.init_controller()?;
         unsafe {
             ZT_Node_setNetconfMaster(
-                self.zt_node,
+                self.zt_node.get(),
                 ctrl_ptr as *mut _,
             )
         };
-        self.controller = Some(controller);
+        *self.controller.borrow_mut() = Some(Rc::from(controller));
         Ok(())
     }
+
+    /// Stops running the controller's work along with the node's and returns
+    /// the controller, if one is registered
+    ///
+    /// The caller then has to answer requests with `process_requests` after
+    /// packets were processed and run `process_background_tasks`
+    /// periodically, on the thread driving the node.
+    pub fn detach_controller(&self) -> Option<Rc<dyn Controller>> {
+        let controller = self.controller.borrow().clone()?;
+        self.controller_inline.set(false);
+        Some(controller)
+    }
+
+    // Returns the controller if its work runs along with the node's
+    fn inline_controller(&self) -> Option<Rc<dyn Controller>> {
+        match self.controller_inline.get() {
+            true => self.controller.borrow().clone(),
+            false => None,
+        }
+    }
 }
 
 impl Drop for Node {
     fn drop(&mut self) {
-        unsafe {
-            ZT_Node_delete(self.zt_node as *mut ZT_Node);
+        if !self.zt_node.get().is_null() {
+            unsafe { ZT_Node_delete(self.zt_node.get()) };
         }
     }
 }
 
+/// Persists state objects of the node
+///
+/// Every object is identified by its type and a two word id, see `StateObject`
+/// for what the id means for each type.
 pub trait StateProvider {
-    fn get_state(&self, object_type: StateObject) -> Fallible<Vec<u8>>;
-    fn set_state(&self, object_type: StateObject, data: &[u8]) -> Fallible<()>;
-}
+    fn get_state(&self, object_type: StateObject, id: &[u64; 2]) -> Fallible<Vec<u8>>;
+    fn set_state(&self, object_type: StateObject, id: &[u64; 2], data: &[u8]) -> Fallible<()>;
+    fn delete_state(&self, object_type: StateObject, id: &[u64; 2]) -> Fallible<()>;
 
-struct PhyWrapper<'a>(&'a dyn PhyProvider);
+    /// Returns ids of all stored objects of a type
+    ///
+    /// Used to orbit stored moons again when the node starts.
+    fn list_state(&self, _object_type: StateObject) -> Fallible<Vec<[u64; 2]>> {
+        Ok(Vec::new())
+    }
+}
 
+/// Sends the node's packets
+///
+/// Addresses are IPv4 or IPv6, packets are only sent through sockets of the
+/// same address family.
 pub trait PhyProvider {
+    /// Returns the number of bytes sent, 0 if the packet was dropped
     fn send(&self, address: &SocketAddr, socket: i64, buf: &[u8]) -> usize;
+    /// Sends through all sockets, returns the number of bytes sent by all of
+    /// them together
     fn send_all(&self, address: &SocketAddr, buf: &[u8]) -> usize;
 }
 
+/// Network controller driven by the node
+///
+/// ZeroTier calls back into the controller at any time while the node runs,
+/// so the node only uses it through shared references.
 pub trait Controller {
     fn init_controller(&self) -> Fallible<*const ()>;
-    fn process_background_tasks(&mut self) -> Fallible<()>;
+    /// Answers the requests received so far, called after every packet
+    fn process_requests(&self) -> Fallible<()>;
+    fn process_background_tasks(&self) -> Fallible<()>;
 }
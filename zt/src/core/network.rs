@@ -0,0 +1,225 @@
+use super::*;
+use std::net::IpAddr;
+use std::os::raw::c_char;
+use ipnetwork::IpNetwork;
+
+/// Status of a joined virtual network
+#[derive(Debug, FromPrimitive, PartialEq, Eq, Clone, Copy)]
+pub enum NetworkStatus {
+    RequestingConfiguration = ZT_VirtualNetworkStatus_ZT_NETWORK_STATUS_REQUESTING_CONFIGURATION as isize,
+    Ok = ZT_VirtualNetworkStatus_ZT_NETWORK_STATUS_OK as isize,
+    AccessDenied = ZT_VirtualNetworkStatus_ZT_NETWORK_STATUS_ACCESS_DENIED as isize,
+    NotFound = ZT_VirtualNetworkStatus_ZT_NETWORK_STATUS_NOT_FOUND as isize,
+    PortError = ZT_VirtualNetworkStatus_ZT_NETWORK_STATUS_PORT_ERROR as isize,
+    ClientTooOld = ZT_VirtualNetworkStatus_ZT_NETWORK_STATUS_CLIENT_TOO_OLD as isize,
+    AuthenticationRequired = ZT_VirtualNetworkStatus_ZT_NETWORK_STATUS_AUTHENTICATION_REQUIRED as isize,
+}
+
+#[derive(Debug, FromPrimitive, PartialEq, Eq, Clone, Copy)]
+pub enum NetworkType {
+    Private = ZT_VirtualNetworkType_ZT_NETWORK_TYPE_PRIVATE as isize,
+    Public = ZT_VirtualNetworkType_ZT_NETWORK_TYPE_PUBLIC as isize,
+}
+
+/// Operation the node performs on a virtual network port
+#[derive(Debug, FromPrimitive, PartialEq, Eq, Clone, Copy)]
+pub enum NetworkConfigOperation {
+    /// Network was joined and should be brought up
+    Up = ZT_VirtualNetworkConfigOperation_ZT_VIRTUAL_NETWORK_CONFIG_OPERATION_UP as isize,
+    /// Network config changed
+    ConfigUpdate = ZT_VirtualNetworkConfigOperation_ZT_VIRTUAL_NETWORK_CONFIG_OPERATION_CONFIG_UPDATE as isize,
+    /// Network is going down, e.g. when the node is shutting down
+    Down = ZT_VirtualNetworkConfigOperation_ZT_VIRTUAL_NETWORK_CONFIG_OPERATION_DOWN as isize,
+    /// Network was left and any state for it should be removed
+    Destroy = ZT_VirtualNetworkConfigOperation_ZT_VIRTUAL_NETWORK_CONFIG_OPERATION_DESTROY as isize,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Route {
+    pub target: IpNetwork,
+    /// Gateway, routes without one are reached directly on the network
+    pub via: Option<IpAddr>,
+    pub flags: u16,
+    pub metric: u16,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Dns {
+    pub domain: String,
+    pub servers: Vec<IpAddr>,
+}
+
+/// Owned copy of `ZT_VirtualNetworkConfig`
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct VirtualNetworkConfig {
+    pub nwid: u64,
+    /// MAC address of this node on the network in the lower 48 bits
+    pub mac: u64,
+    pub name: String,
+    pub status: NetworkStatus,
+    pub network_type: NetworkType,
+    pub mtu: u32,
+    pub dhcp: bool,
+    pub bridge: bool,
+    pub broadcast_enabled: bool,
+    /// Error code returned by the network config handler, 0 if there was none
+    pub port_error: i32,
+    pub revision: u64,
+    pub assigned_addresses: Vec<IpNetwork>,
+    pub routes: Vec<Route>,
+    /// Multicast groups (MAC and ADI) the node is subscribed to
+    pub multicast_subscriptions: Vec<(u64, u32)>,
+    /// DNS settings, only set if the network pushes a DNS domain
+    pub dns: Option<Dns>,
+}
+
+impl VirtualNetworkConfig {
+    /// Returns the MAC address as bytes
+    pub fn mac_address(&self) -> [u8; 6] {
+        let b = self.mac.to_be_bytes();
+        [b[2], b[3], b[4], b[5], b[6], b[7]]
+    }
+}
+
+impl From<&ZT_VirtualNetworkConfig> for VirtualNetworkConfig {
+    fn from(conf: &ZT_VirtualNetworkConfig) -> Self {
+        let address_count = std::cmp::min(conf.assignedAddressCount as usize, conf.assignedAddresses.len());
+        let route_count = std::cmp::min(conf.routeCount as usize, conf.routes.len());
+        let multicast_count = std::cmp::min(conf.multicastSubscriptionCount as usize, conf.multicastSubscriptions.len());
+
+        let domain = c_string(&conf.dns.domain);
+        let dns = match domain.is_empty() {
+            true => None,
+            false => Some(Dns {
+                domain: domain,
+                servers: conf.dns.server_addr.iter()
+                    .filter_map(|addr| to_socket_addr(addr).map(|a| a.ip()))
+                    .collect(),
+            }),
+        };
+
+        Self {
+            nwid: conf.nwid,
+            mac: conf.mac,
+            name: c_string(&conf.name),
+            status: NetworkStatus::from_u32(conf.status).unwrap_or(NetworkStatus::PortError),
+            network_type: NetworkType::from_u32(conf.type_).unwrap_or(NetworkType::Private),
+            mtu: conf.mtu,
+            dhcp: conf.dhcp != 0,
+            bridge: conf.bridge != 0,
+            broadcast_enabled: conf.broadcastEnabled != 0,
+            port_error: conf.portError,
+            revision: conf.netconfRevision as u64,
+            assigned_addresses: conf.assignedAddresses[..address_count].iter()
+                .filter_map(to_ip_network)
+                .collect(),
+            routes: conf.routes[..route_count].iter()
+                .filter_map(|route| Some(Route {
+                    target: to_ip_network(&route.target)?,
+                    via: to_socket_addr(&route.via).map(|a| a.ip()),
+                    flags: route.flags,
+                    metric: route.metric,
+                }))
+                .collect(),
+            multicast_subscriptions: conf.multicastSubscriptions[..multicast_count].iter()
+                .map(|sub| (sub.mac, sub.adi))
+                .collect(),
+            dns: dns,
+        }
+    }
+}
+
+/// Receives changes to the config of joined networks
+pub trait NetworkConfigHandler {
+    /// Returning an error puts the network in `NetworkStatus::PortError`
+    fn on_network_config(&self, operation: NetworkConfigOperation, config: &VirtualNetworkConfig) -> Fallible<()>;
+}
+
+/// Ethernet frame on a virtual network
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct Frame<'a> {
+    pub nwid: u64,
+    /// Source MAC address in the lower 48 bits
+    pub source: u64,
+    /// Destination MAC address in the lower 48 bits
+    pub destination: u64,
+    pub ether_type: u16,
+    pub vlan_id: u16,
+    pub data: &'a [u8],
+}
+
+/// Receives Ethernet frames the node delivers to a joined network
+pub trait FrameHandler {
+    fn on_frame(&self, frame: &Frame);
+}
+
+// Reads a null terminated string from a fixed size C buffer
+fn c_string(buf: &[c_char]) -> String {
+    let bytes: Vec<u8> = buf.iter()
+        .take_while(|c| **c != 0)
+        .map(|c| *c as u8)
+        .collect();
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+// Unset addresses have family 0 and are returned as None
+fn to_socket_addr(addr: &sockaddr_storage) -> Option<SocketAddr> {
+    sockaddr_to_addr(addr, std::mem::size_of::<sockaddr_storage>()).ok()
+}
+
+// ZeroTier stores the netmask bits of addresses and routes in the port field
+fn to_ip_network(addr: &sockaddr_storage) -> Option<IpNetwork> {
+    let addr = to_socket_addr(addr)?;
+    IpNetwork::new(addr.ip(), addr.port() as u8).ok()
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    fn to_sockaddr(addr: &str) -> sockaddr_storage {
+        let mut sockaddr: sockaddr_storage = unsafe { std::mem::zeroed() };
+        addr_to_sockaddr(SocketAddr::from_str(addr).unwrap(), &mut sockaddr);
+        sockaddr
+    }
+
+    #[test]
+    fn test_from_raw_config() {
+        let mut raw: Box<ZT_VirtualNetworkConfig> = Box::new(unsafe { std::mem::zeroed() });
+        raw.nwid = 0xba7a59abb06f066b;
+        raw.mac = 0x32a1b2c3d4e5;
+        raw.name[..4].copy_from_slice(&[b't' as c_char, b'e' as c_char, b's' as c_char, b't' as c_char]);
+        raw.status = ZT_VirtualNetworkStatus_ZT_NETWORK_STATUS_OK;
+        raw.mtu = 2800;
+        raw.broadcastEnabled = 1;
+        raw.netconfRevision = 3;
+        raw.assignedAddressCount = 1;
+        raw.assignedAddresses[0] = to_sockaddr("10.147.20.5:24");
+        raw.routeCount = 2;
+        raw.routes[0].target = to_sockaddr("10.147.20.0:24");
+        raw.routes[1].target = to_sockaddr("0.0.0.0:0");
+        raw.routes[1].via = to_sockaddr("10.147.20.1:0");
+
+        let conf = VirtualNetworkConfig::from(&*raw);
+        assert_eq!(conf.nwid, 0xba7a59abb06f066b);
+        assert_eq!(conf.mac_address(), [0x32, 0xa1, 0xb2, 0xc3, 0xd4, 0xe5]);
+        assert_eq!(conf.name, "test");
+        assert_eq!(conf.status, NetworkStatus::Ok);
+        assert_eq!(conf.network_type, NetworkType::Private);
+        assert_eq!(conf.mtu, 2800);
+        assert!(conf.broadcast_enabled);
+        assert_eq!(conf.revision, 3);
+        assert_eq!(conf.assigned_addresses, vec![IpNetwork::from_str("10.147.20.5/24").unwrap()]);
+        assert_eq!(conf.routes, vec![
+            Route { target: IpNetwork::from_str("10.147.20.0/24").unwrap(), via: None, flags: 0, metric: 0 },
+            Route {
+                target: IpNetwork::from_str("0.0.0.0/0").unwrap(),
+                via: Some(IpAddr::from_str("10.147.20.1").unwrap()),
+                flags: 0,
+                metric: 0,
+            },
+        ]);
+        assert_eq!(conf.dns, None);
+    }
+}
@@ -0,0 +1,41 @@
+use std::net::SocketAddr;
+
+/// Address family ZeroTier is asking for a path in
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum AddressFamily {
+    Any,
+    Ipv4,
+    Ipv6,
+}
+
+impl AddressFamily {
+    pub(crate) fn from_raw(family: i32) -> Option<Self> {
+        match family {
+            -1 => Some(AddressFamily::Any),
+            libc::AF_INET => Some(AddressFamily::Ipv4),
+            libc::AF_INET6 => Some(AddressFamily::Ipv6),
+            _ => None,
+        }
+    }
+
+    pub fn matches(&self, address: &SocketAddr) -> bool {
+        match self {
+            AddressFamily::Any => true,
+            AddressFamily::Ipv4 => address.is_ipv4(),
+            AddressFamily::Ipv6 => address.is_ipv6(),
+        }
+    }
+}
+
+/// Decides which physical paths the node may use to reach peers
+pub trait PathPolicy {
+    /// Returns false to forbid using a physical address to reach a peer
+    fn check_path(&self, _address: u64, _socket: i64, _path: &SocketAddr) -> bool {
+        true
+    }
+
+    /// Returns a static physical address for a peer, if one is known
+    fn lookup_path(&self, _address: u64, _family: AddressFamily) -> Option<SocketAddr> {
+        None
+    }
+}
@@ -0,0 +1,152 @@
+use super::*;
+use std::path::PathBuf;
+use std::io::ErrorKind;
+
+/// State provider storing state objects in a directory using the same layout
+/// as ZeroTierOne
+///
+/// ```text
+/// identity.public
+/// identity.secret
+/// planet
+/// moons.d/<moon id>.moon
+/// peers.d/<address>.peer
+/// networks.d/<network id>.conf
+/// ```
+pub struct DirectoryState {
+    path: PathBuf,
+}
+
+impl DirectoryState {
+    pub fn new(path: &str) -> Self {
+        Self {
+            path: PathBuf::from(path),
+        }
+    }
+
+    // Returns the path of a state object in the directory
+    fn object_path(&self, object_type: &StateObject, id: &[u64; 2]) -> Option<PathBuf> {
+        let path = match object_type {
+            StateObject::Null => return None,
+            StateObject::PublicIdentity => self.path.join("identity.public"),
+            StateObject::SecretIdentity => self.path.join("identity.secret"),
+            StateObject::Planet => self.path.join("planet"),
+            StateObject::Moon => self.path.join("moons.d").join(format!("{:016x}.moon", id[0])),
+            StateObject::Peer => self.path.join("peers.d").join(format!("{:010x}.peer", id[0])),
+            StateObject::NetworkConfig => self.path.join("networks.d").join(format!("{:016x}.conf", id[0])),
+        };
+        Some(path)
+    }
+
+    // Returns directory and file extension of object types stored one file per id
+    fn object_dir(&self, object_type: &StateObject) -> Option<(PathBuf, &'static str)> {
+        match object_type {
+            StateObject::Moon => Some((self.path.join("moons.d"), "moon")),
+            StateObject::Peer => Some((self.path.join("peers.d"), "peer")),
+            StateObject::NetworkConfig => Some((self.path.join("networks.d"), "conf")),
+            _ => None,
+        }
+    }
+}
+
+impl StateProvider for DirectoryState {
+    fn get_state(&self, object_type: StateObject, id: &[u64; 2]) -> Fallible<Vec<u8>> {
+        let path = self.object_path(&object_type, id).ok_or(StateError::NotFound)?;
+        match std::fs::read(path) {
+            Ok(data) => Ok(data),
+            Err(err) if err.kind() == ErrorKind::NotFound => Err(StateError::NotFound.into()),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    fn set_state(&self, object_type: StateObject, id: &[u64; 2], data: &[u8]) -> Fallible<()> {
+        let path = self.object_path(&object_type, id).ok_or(StateError::NotFound)?;
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(&path, data)?;
+
+        // Nobody but us should be able to read the secret identity
+        #[cfg(unix)]
+        if object_type == StateObject::SecretIdentity {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600))?;
+        }
+
+        Ok(())
+    }
+
+    fn delete_state(&self, object_type: StateObject, id: &[u64; 2]) -> Fallible<()> {
+        let path = self.object_path(&object_type, id).ok_or(StateError::NotFound)?;
+        match std::fs::remove_file(path) {
+            Ok(()) => Ok(()),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(()),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    fn list_state(&self, object_type: StateObject) -> Fallible<Vec<[u64; 2]>> {
+        let (dir, extension) = match self.object_dir(&object_type) {
+            Some(dir) => dir,
+            None => return Ok(Vec::new()),
+        };
+        let entries = match std::fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => return Err(err.into()),
+        };
+
+        let mut ids = Vec::new();
+        for entry in entries {
+            let path = entry?.path();
+            if path.extension().and_then(|e| e.to_str()) != Some(extension) {
+                continue;
+            }
+            // Files not named by an id are ignored
+            if let Some(id) = path.file_stem().and_then(|s| s.to_str()).and_then(|s| u64::from_str_radix(s, 16).ok()) {
+                ids.push([id, 0]);
+            }
+        }
+        ids.sort();
+        Ok(ids)
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    #[test]
+    fn test_directory_state() -> Fallible<()> {
+        let dir = std::env::temp_dir().join(format!("rztc-state-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let state = DirectoryState::new(dir.to_str().unwrap());
+
+        let id = [0x99e5a948c2, 0];
+        assert!(state.get_state(StateObject::Peer, &id).is_err());
+
+        state.set_state(StateObject::Peer, &id, b"peer")?;
+        state.set_state(StateObject::NetworkConfig, &[0xba7a59abb06f066b, 0], b"conf")?;
+        state.set_state(StateObject::Planet, &[0, 0], b"planet")?;
+
+        assert_eq!(std::fs::read(dir.join("peers.d/99e5a948c2.peer"))?, b"peer");
+        assert_eq!(std::fs::read(dir.join("networks.d/ba7a59abb06f066b.conf"))?, b"conf");
+        assert_eq!(state.get_state(StateObject::Planet, &[0, 0])?, b"planet");
+
+        state.set_state(StateObject::Moon, &[0x2a, 0], b"moon")?;
+        std::fs::write(dir.join("moons.d/README"), b"not a moon")?;
+        assert_eq!(state.list_state(StateObject::Moon)?, vec![[0x2a, 0]]);
+        assert_eq!(state.list_state(StateObject::Peer)?, vec![id]);
+        assert!(state.list_state(StateObject::Planet)?.is_empty());
+
+        state.delete_state(StateObject::Peer, &id)?;
+        assert!(state.get_state(StateObject::Peer, &id).is_err());
+        assert!(state.list_state(StateObject::Peer)?.is_empty());
+        // Deleting something that doesn't exist is not an error
+        state.delete_state(StateObject::Peer, &id)?;
+
+        std::fs::remove_dir_all(&dir)?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,104 @@
+use super::*;
+use serde::{Serialize, Deserialize};
+
+/// World id of ZeroTier's default planet, used when no custom planet is stored
+pub const WORLD_ID_EARTH: u64 = 149604618;
+
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub struct NodeStatus {
+    pub address: u64,
+    pub public_identity: String,
+    pub online: bool,
+    /// Id of the planet the node is using
+    pub world_id: u64,
+}
+
+#[derive(Debug, FromPrimitive, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+pub enum PeerRole {
+    Leaf = ZT_PeerRole_ZT_PEER_ROLE_LEAF as isize,
+    Moon = ZT_PeerRole_ZT_PEER_ROLE_MOON as isize,
+    Planet = ZT_PeerRole_ZT_PEER_ROLE_PLANET as isize,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub struct PeerPath {
+    pub address: SocketAddr,
+    /// Local socket the path is using, -1 if unspecified
+    pub local_socket: i64,
+    /// Milliseconds since epoch of last packet sent and received on the path
+    pub last_send: u64,
+    pub last_receive: u64,
+    /// Trusted path id, 0 if the path is not trusted
+    pub trusted_path_id: u64,
+    pub preferred: bool,
+    pub expired: bool,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub struct Peer {
+    pub address: u64,
+    pub role: PeerRole,
+    /// Version (major, minor, revision), None if unknown
+    pub version: Option<(u32, u32, u32)>,
+    /// Latency in milliseconds, None if unknown
+    pub latency: Option<u32>,
+    pub paths: Vec<PeerPath>,
+}
+
+impl From<&ZT_Peer> for Peer {
+    fn from(peer: &ZT_Peer) -> Self {
+        let path_count = std::cmp::min(peer.pathCount as usize, peer.paths.len());
+        let version = match peer.versionMajor >= 0 {
+            true => Some((peer.versionMajor as u32, peer.versionMinor as u32, peer.versionRev as u32)),
+            false => None,
+        };
+
+        Self {
+            address: peer.address,
+            role: PeerRole::from_u32(peer.role).unwrap_or(PeerRole::Leaf),
+            version: version,
+            latency: u32::try_from(peer.latency).ok(),
+            paths: peer.paths[..path_count].iter()
+                .filter_map(|path| Some(PeerPath {
+                    address: sockaddr_to_addr(&path.address, std::mem::size_of::<sockaddr_storage>()).ok()?,
+                    local_socket: path.localSocket as i64,
+                    last_send: path.lastSend,
+                    last_receive: path.lastReceive,
+                    trusted_path_id: path.trustedPathId,
+                    preferred: path.preferred != 0,
+                    expired: path.expired != 0,
+                }))
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn test_from_raw_peer() {
+        let mut raw: Box<ZT_Peer> = Box::new(unsafe { std::mem::zeroed() });
+        raw.address = 0x99e5a948c2;
+        raw.versionMajor = -1;
+        raw.latency = -1;
+        raw.role = ZT_PeerRole_ZT_PEER_ROLE_PLANET;
+        raw.pathCount = 1;
+        addr_to_sockaddr(SocketAddr::from_str("192.0.2.1:9993").unwrap(), &mut raw.paths[0].address);
+        raw.paths[0].lastReceive = 1000;
+        raw.paths[0].preferred = 1;
+
+        let peer = Peer::from(&*raw);
+        assert_eq!(peer.address, 0x99e5a948c2);
+        assert_eq!(peer.role, PeerRole::Planet);
+        assert_eq!(peer.version, None);
+        assert_eq!(peer.latency, None);
+        assert_eq!(peer.paths.len(), 1);
+        assert_eq!(peer.paths[0].address, SocketAddr::from_str("192.0.2.1:9993").unwrap());
+        assert_eq!(peer.paths[0].last_receive, 1000);
+        assert!(peer.paths[0].preferred);
+        assert!(!peer.paths[0].expired);
+    }
+}
@@ -44,6 +44,31 @@ impl Dictionary {
         Err(DictionaryError::WrongType.into())
     }
 
+    pub fn get_bytes(&self, key: &str) -> Fallible<Vec<u8>> {
+        let val = self.get_key(key)?;
+        let mut buf = Vec::with_capacity(val.len());
+        let mut iter = val.iter();
+        while let Some(&c) = iter.next() {
+            if c != 92 {
+                buf.push(c);
+                continue;
+            }
+            match iter.next() {
+                Some(b'0') => buf.push(0),  // Binary 0
+                Some(b'r') => buf.push(13), // \r
+                Some(b'n') => buf.push(10), // \n
+                Some(b'e') => buf.push(61), // =
+                Some(&c) => buf.push(c),
+                None => break,
+            }
+        }
+        Ok(buf)
+    }
+
+    pub fn get_str(&self, key: &str) -> Fallible<String> {
+        Ok(String::from_utf8(self.get_bytes(key)?)?)
+    }
+
     fn get_key(&self, key: &str) -> Fallible<&[u8]> {
         let iter = self.0.split(|item| *item == '\n' as u8);
         for pair in iter {
@@ -108,3 +133,22 @@ impl fmt::Debug for Dictionary {
         Ok(())
     }
 }
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    #[test]
+    fn test_escaped_bytes() -> Fallible<()> {
+        let value = b"a=b\\c\n\r\0d";
+        let mut dict = Dictionary::new();
+        dict.set_bytes("v", value);
+        dict.set_str("event", "1002");
+
+        assert_eq!(dict.get_bytes("v")?, value.to_vec());
+        assert_eq!(dict.get_str("event")?, "1002");
+        assert!(dict.get_str("missing").is_err());
+
+        Ok(())
+    }
+}
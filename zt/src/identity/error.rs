@@ -0,0 +1,9 @@
+use failure::Fail;
+
+#[derive(Debug, Fail)]
+pub enum IdentityError {
+    #[fail(display = "invalid identity {}", _0)]
+    Invalid(String),
+    #[fail(display = "identity has no secret key")]
+    NoSecret,
+}
@@ -0,0 +1,276 @@
+mod error;
+mod salsa20;
+
+pub use error::*;
+use crate::controller::ZeroTierSigner;
+use salsa20::Salsa20;
+use failure::Fallible;
+use sha2::Digest;
+use rand::RngCore;
+use ed25519_dalek::{Keypair, PublicKey, SecretKey, Signature, Signer, Verifier};
+use std::fmt;
+use std::str::FromStr;
+
+const KEY_LENGTH: usize = 64;
+const SIGNATURE_LENGTH: usize = 96;
+
+// Memory used to derive an address, like ZT_IDENTITY_GEN_MEMORY
+const GEN_MEMORY: usize = 2097152;
+// The first byte of the hash of a valid public key has to be below this
+const GEN_HASHCASH_FIRST_BYTE_LESS_THAN: u8 = 17;
+
+/// ZeroTier identity, with or without its secret key
+///
+/// Identities are read and written in the format of identity.secret and
+/// identity.public, `address:0:public` with the secret key appended to
+/// secret identities. Keys use the same layout as `WorldKeypair`.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Identity {
+    pub address: u64,
+    pub public: [u8; KEY_LENGTH],
+    secret: Option<[u8; KEY_LENGTH]>,
+}
+
+impl Identity {
+    /// Generates a new identity
+    ///
+    /// Like ZeroTier only the curve25519 key is changed until the address
+    /// derived from the public key satisfies the hashcash requirement, which
+    /// takes a few seconds.
+    pub fn generate() -> Self {
+        let mut genmem = vec![0u8; GEN_MEMORY];
+        loop {
+            let mut secret = [0u8; KEY_LENGTH];
+            rand::rngs::OsRng.fill_bytes(&mut secret);
+            let mut public = [0u8; KEY_LENGTH];
+            public[32..].copy_from_slice(ed25519_public(&secret).as_bytes());
+
+            let digest = loop {
+                let a = u64::from_le_bytes(secret[8..16].try_into().unwrap()).wrapping_add(1);
+                let b = u64::from_le_bytes(secret[16..24].try_into().unwrap()).wrapping_sub(1);
+                secret[8..16].copy_from_slice(&a.to_le_bytes());
+                secret[16..24].copy_from_slice(&b.to_le_bytes());
+                public[..32].copy_from_slice(&curve25519_public(&secret));
+
+                let digest = memory_hard_hash(&public, &mut genmem);
+                if digest[0] < GEN_HASHCASH_FIRST_BYTE_LESS_THAN {
+                    break digest;
+                }
+            };
+
+            let address = address_from_digest(&digest);
+            if !is_reserved(address) {
+                return Self {
+                    address: address,
+                    public: public,
+                    secret: Some(secret),
+                };
+            }
+        }
+    }
+
+    pub fn has_secret(&self) -> bool {
+        self.secret.is_some()
+    }
+
+    /// Returns the identity without its secret key
+    pub fn to_public(&self) -> Self {
+        Self {
+            address: self.address,
+            public: self.public,
+            secret: None,
+        }
+    }
+
+    /// Returns true if the address was derived from the public key and the
+    /// secret key belongs to it, like `Identity::locallyValidate`
+    pub fn validate(&self) -> bool {
+        if is_reserved(self.address) {
+            return false;
+        }
+        if let Some(secret) = &self.secret {
+            if curve25519_public(secret) != self.public[..32] || ed25519_public(secret).as_bytes() != &self.public[32..] {
+                return false;
+            }
+        }
+        let digest = memory_hard_hash(&self.public, &mut vec![0u8; GEN_MEMORY]);
+        digest[0] < GEN_HASHCASH_FIRST_BYTE_LESS_THAN && address_from_digest(&digest) == self.address
+    }
+
+    /// Returns true if the signature was made by the identity
+    pub fn verify(&self, data: &[u8], signature: &[u8]) -> bool {
+        if signature.len() != SIGNATURE_LENGTH {
+            return false;
+        }
+        let public = match PublicKey::from_bytes(&self.public[32..]) {
+            Ok(public) => public,
+            Err(_) => return false,
+        };
+        let ed25519 = match Signature::from_bytes(&signature[..64]) {
+            Ok(signature) => signature,
+            Err(_) => return false,
+        };
+        let digest = &sha2::Sha512::digest(data)[..32];
+        digest == &signature[64..] && public.verify(digest, &ed25519).is_ok()
+    }
+}
+
+impl ZeroTierSigner for Identity {
+    fn sign(&self, data: &[u8]) -> Fallible<[u8; 96]> {
+        let secret = self.secret.as_ref().ok_or(IdentityError::NoSecret)?;
+        let ed25519 = SecretKey::from_bytes(&secret[32..]).map_err(|_| IdentityError::NoSecret)?;
+        let keypair = Keypair {
+            public: PublicKey::from(&ed25519),
+            secret: ed25519,
+        };
+
+        // Same signature format as the controller uses
+        let mut signature = [0u8; SIGNATURE_LENGTH];
+        let digest = &sha2::Sha512::digest(data)[..32];
+        signature[..64].copy_from_slice(&keypair.sign(digest).to_bytes());
+        signature[64..].copy_from_slice(digest);
+        Ok(signature)
+    }
+}
+
+impl fmt::Display for Identity {
+    /// Formats the identity like identity.secret, or identity.public if it
+    /// has no secret key
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:010x}:0:{}", self.address, hex::encode(self.public))?;
+        if let Some(secret) = &self.secret {
+            write!(f, ":{}", hex::encode(secret))?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for Identity {
+    // Never prints the secret key
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Identity({})", self.to_public())
+    }
+}
+
+impl FromStr for Identity {
+    type Err = failure::Error;
+
+    fn from_str(s: &str) -> Fallible<Self> {
+        let invalid = || IdentityError::Invalid(s.to_string());
+        let fields: Vec<&str> = s.trim().split(':').collect();
+        if fields.len() < 3 || fields.len() > 4 || fields[0].len() != 10 || fields[1] != "0" {
+            return Err(invalid().into());
+        }
+
+        let mut address = [0u8; 8];
+        hex::decode_to_slice(fields[0], &mut address[3..]).map_err(|_| invalid())?;
+        let mut public = [0u8; KEY_LENGTH];
+        hex::decode_to_slice(fields[2], &mut public).map_err(|_| invalid())?;
+        let secret = match fields.get(3) {
+            Some(field) => {
+                let mut secret = [0u8; KEY_LENGTH];
+                hex::decode_to_slice(field, &mut secret).map_err(|_| invalid())?;
+                Some(secret)
+            },
+            None => None,
+        };
+
+        Ok(Self {
+            address: u64::from_be_bytes(address),
+            public: public,
+            secret: secret,
+        })
+    }
+}
+
+fn curve25519_public(secret: &[u8; KEY_LENGTH]) -> [u8; 32] {
+    let key: [u8; 32] = secret[..32].try_into().unwrap();
+    x25519_dalek::PublicKey::from(&x25519_dalek::StaticSecret::from(key)).to_bytes()
+}
+
+fn ed25519_public(secret: &[u8; KEY_LENGTH]) -> PublicKey {
+    // Any 32 bytes are a valid ed25519 secret key
+    PublicKey::from(&SecretKey::from_bytes(&secret[32..]).unwrap())
+}
+
+// The last 5 bytes of the hash are the address
+fn address_from_digest(digest: &[u8; 64]) -> u64 {
+    let mut address = [0u8; 8];
+    address[3..].copy_from_slice(&digest[59..]);
+    u64::from_be_bytes(address)
+}
+
+// Addresses starting with 0xff are reserved, like Address::isReserved
+fn is_reserved(address: u64) -> bool {
+    address == 0 || (address >> 32) == 0xff
+}
+
+// Hashes the public key using genmem, like _computeMemoryHardHash
+//
+// genmem is filled with Salsa20 chained like CBC so it has to be computed
+// sequentially, and then used as a lookup table to render the digest.
+fn memory_hard_hash(public: &[u8; KEY_LENGTH], genmem: &mut [u8]) -> [u8; 64] {
+    let mut digest = [0u8; 64];
+    digest.copy_from_slice(&sha2::Sha512::digest(public));
+
+    let mut s20 = Salsa20::new(digest[..32].try_into().unwrap(), digest[32..40].try_into().unwrap());
+    genmem.fill(0);
+    s20.crypt(&mut genmem[..64]);
+    for i in (64..GEN_MEMORY).step_by(64) {
+        genmem.copy_within(i - 64..i, i);
+        s20.crypt(&mut genmem[i..i + 64]);
+    }
+
+    let word = |genmem: &[u8], i: usize| u64::from_be_bytes(genmem[i * 8..i * 8 + 8].try_into().unwrap());
+    for i in (0..GEN_MEMORY / 8).step_by(2) {
+        let idx1 = (word(genmem, i) % 8) as usize * 8;
+        let idx2 = (word(genmem, i + 1) % (GEN_MEMORY / 8) as u64) as usize * 8;
+        let tmp: [u8; 8] = genmem[idx2..idx2 + 8].try_into().unwrap();
+        genmem[idx2..idx2 + 8].copy_from_slice(&digest[idx1..idx1 + 8]);
+        digest[idx1..idx1 + 8].copy_from_slice(&tmp);
+        s20.crypt(&mut digest);
+    }
+    digest
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    // Identity of the controller in the controller tests
+    const PUBLIC: &str = "894f8955a6:0:2ca7d749ec20a750b6189cf1f51a5f7db67bbed6218cbae506946c01e267cd05d6e4bd580af21231b7edd03eb04a086a43a14cfca67b19a1cc4484e5ad142034";
+
+    #[test]
+    fn test_validate() -> Fallible<()> {
+        let identity: Identity = PUBLIC.parse()?;
+        assert_eq!(identity.address, 0x894f8955a6);
+        assert!(!identity.has_secret());
+        assert!(identity.validate());
+        assert_eq!(identity.to_string(), PUBLIC);
+
+        let mut forged = identity.clone();
+        forged.address ^= 1;
+        assert!(!forged.validate());
+
+        assert!("894f8955a6:0:2ca7".parse::<Identity>().is_err());
+        assert!("894f8955a6:1:".parse::<Identity>().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_generate_sign_verify() -> Fallible<()> {
+        let identity = Identity::generate();
+        assert!(identity.validate());
+
+        let parsed: Identity = identity.to_string().parse()?;
+        assert_eq!(parsed, identity);
+        assert_eq!(identity.to_string().len(), 270);
+        assert_eq!(identity.to_public().to_string().len(), 141);
+
+        let signature = identity.sign(b"hello")?;
+        assert!(identity.to_public().verify(b"hello", &signature));
+        assert!(!identity.verify(b"hallo", &signature));
+        assert!(identity.to_public().sign(b"hello").is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,58 @@
+// Salsa20/20 with a 256 bit key and a 64 bit IV, only used to derive
+// addresses, so it is only ever applied to whole 64 byte blocks
+
+const SIGMA: [u32; 4] = [0x61707865, 0x3320646e, 0x79622d32, 0x6b206574];
+
+pub struct Salsa20 {
+    state: [u32; 16],
+}
+
+impl Salsa20 {
+    pub fn new(key: &[u8; 32], iv: &[u8; 8]) -> Self {
+        let word = |buf: &[u8], i: usize| u32::from_le_bytes(buf[i * 4..i * 4 + 4].try_into().unwrap());
+        Self {
+            state: [
+                SIGMA[0], word(key, 0), word(key, 1), word(key, 2),
+                word(key, 3), SIGMA[1], word(iv, 0), word(iv, 1),
+                0, 0, SIGMA[2], word(key, 4),
+                word(key, 5), word(key, 6), word(key, 7), SIGMA[3],
+            ],
+        }
+    }
+
+    /// Encrypts (or decrypts) a block in place
+    pub fn crypt(&mut self, block: &mut [u8]) {
+        let mut x = self.state;
+        for _ in 0..10 {
+            // Column round
+            quarter_round(&mut x, 0, 4, 8, 12);
+            quarter_round(&mut x, 5, 9, 13, 1);
+            quarter_round(&mut x, 10, 14, 2, 6);
+            quarter_round(&mut x, 15, 3, 7, 11);
+            // Row round
+            quarter_round(&mut x, 0, 1, 2, 3);
+            quarter_round(&mut x, 5, 6, 7, 4);
+            quarter_round(&mut x, 10, 11, 8, 9);
+            quarter_round(&mut x, 15, 12, 13, 14);
+        }
+        for (i, chunk) in block.chunks_mut(4).enumerate() {
+            let key = x[i].wrapping_add(self.state[i]).to_le_bytes();
+            for (b, k) in chunk.iter_mut().zip(key) {
+                *b ^= k;
+            }
+        }
+
+        // 64 bit block counter
+        self.state[8] = self.state[8].wrapping_add(1);
+        if self.state[8] == 0 {
+            self.state[9] = self.state[9].wrapping_add(1);
+        }
+    }
+}
+
+fn quarter_round(x: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+    x[b] ^= x[a].wrapping_add(x[d]).rotate_left(7);
+    x[c] ^= x[b].wrapping_add(x[a]).rotate_left(9);
+    x[d] ^= x[c].wrapping_add(x[b]).rotate_left(13);
+    x[a] ^= x[d].wrapping_add(x[c]).rotate_left(18);
+}
@@ -6,9 +6,12 @@ extern crate num_derive;
 extern crate sha2;
 extern crate bincode;
 extern crate serde;
+extern crate serde_json;
 extern crate ipnetwork;
 
 pub mod core;
 pub mod controller;
 pub mod dictionary;
+pub mod world;
+pub mod identity;
 
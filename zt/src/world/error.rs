@@ -0,0 +1,19 @@
+use failure::Fail;
+
+#[derive(Debug, Fail)]
+pub enum WorldError {
+    #[fail(display = "world definition is truncated")]
+    Truncated,
+    #[fail(display = "unknown world type {}", _0)]
+    UnknownType(u8),
+    #[fail(display = "a world needs between 1 and {} roots", _0)]
+    RootCount(usize),
+    #[fail(display = "a root can have at most {} stable endpoints", _0)]
+    EndpointCount(usize),
+    #[fail(display = "invalid identity {}", _0)]
+    InvalidIdentity(String),
+    #[fail(display = "invalid world keypair")]
+    InvalidKeypair,
+    #[fail(display = "unknown address type {}", _0)]
+    UnknownAddressType(u8),
+}
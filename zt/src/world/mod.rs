@@ -0,0 +1,459 @@
+mod error;
+
+pub use error::*;
+use crate::controller::ZeroTierSigner;
+use failure::Fallible;
+use sha2::Digest;
+use ed25519_dalek::{Keypair, PublicKey, Signature, Signer, Verifier, KEYPAIR_LENGTH};
+use std::net::{IpAddr, SocketAddr};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Max number of roots in a world, like ZT_WORLD_MAX_ROOTS
+pub const MAX_ROOTS: usize = 4;
+/// Max number of stable endpoints of a root, like ZT_WORLD_MAX_STABLE_ENDPOINTS_PER_ROOT
+pub const MAX_STABLE_ENDPOINTS: usize = 32;
+
+const PUBLIC_KEY_LENGTH: usize = 64;
+const SIGNATURE_LENGTH: usize = 96;
+
+// Wrap the world when it is serialized for signing
+const SIGN_PREFIX: u64 = 0x7f7f7f7f7f7f7f7f;
+const SIGN_SUFFIX: u64 = 0xf7f7f7f7f7f7f7f7;
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum WorldType {
+    Planet = 1,
+    Moon = 127,
+}
+
+/// Root server of a world
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Root {
+    pub address: u64,
+    pub public: [u8; PUBLIC_KEY_LENGTH],
+    /// Physical addresses the root can always be reached at
+    pub stable_endpoints: Vec<SocketAddr>,
+}
+
+impl Root {
+    /// Creates a root from its public identity, as found in identity.public
+    ///
+    /// A secret identity is accepted too, only its public part is used.
+    pub fn new(identity: &str, stable_endpoints: Vec<SocketAddr>) -> Fallible<Self> {
+        let invalid = || WorldError::InvalidIdentity(identity.to_string());
+        let fields: Vec<&str> = identity.trim().split(':').collect();
+        if fields.len() < 3 || fields[0].len() != 10 || fields[1] != "0" {
+            return Err(invalid().into());
+        }
+
+        let mut address = [0u8; 8];
+        hex::decode_to_slice(fields[0], &mut address[3..]).map_err(|_| invalid())?;
+        let mut public = [0u8; PUBLIC_KEY_LENGTH];
+        hex::decode_to_slice(fields[2], &mut public).map_err(|_| invalid())?;
+
+        Ok(Self {
+            address: u64::from_be_bytes(address),
+            public: public,
+            stable_endpoints: stable_endpoints,
+        })
+    }
+}
+
+/// Keypair updates of a world have to be signed with
+///
+/// The key layout matches ZeroTier's C25519 keys, so keys created by mkworld
+/// (current.c25519) can be used as well.
+///
+/// ```text
+/// |--       public        --|--       secret        --|
+/// |--   32   --|--   32  --|--   32   --|--   32   --|
+/// | curve25519 |  ed25519  | curve25519 |  ed25519   |
+/// ```
+pub struct WorldKeypair {
+    curve25519: [u8; 32],
+    curve25519_public: [u8; 32],
+    ed25519: Keypair,
+}
+
+impl WorldKeypair {
+    pub fn generate() -> Self {
+        let mut rng = rand::rngs::OsRng;
+        let curve25519 = x25519_dalek::StaticSecret::new(&mut rng);
+        Self {
+            curve25519_public: x25519_dalek::PublicKey::from(&curve25519).to_bytes(),
+            curve25519: curve25519.to_bytes(),
+            ed25519: Keypair::generate(&mut rng),
+        }
+    }
+
+    pub fn from_bytes(buf: &[u8]) -> Fallible<Self> {
+        if buf.len() != PUBLIC_KEY_LENGTH * 2 {
+            return Err(WorldError::InvalidKeypair.into());
+        }
+        // ed25519_dalek::Keypair::from_bytes expects the secret key first
+        let mut keys = [0u8; KEYPAIR_LENGTH];
+        keys[..32].copy_from_slice(&buf[96..128]);
+        keys[32..].copy_from_slice(&buf[32..64]);
+        let ed25519 = Keypair::from_bytes(&keys).map_err(|_| WorldError::InvalidKeypair)?;
+
+        Ok(Self {
+            curve25519: buf[64..96].try_into().unwrap(),
+            curve25519_public: buf[..32].try_into().unwrap(),
+            ed25519: ed25519,
+        })
+    }
+
+    pub fn to_bytes(&self) -> [u8; PUBLIC_KEY_LENGTH * 2] {
+        let mut buf = [0u8; PUBLIC_KEY_LENGTH * 2];
+        buf[..64].copy_from_slice(&self.public());
+        buf[64..96].copy_from_slice(&self.curve25519);
+        buf[96..].copy_from_slice(self.ed25519.secret.as_bytes());
+        buf
+    }
+
+    pub fn public(&self) -> [u8; PUBLIC_KEY_LENGTH] {
+        let mut public = [0u8; PUBLIC_KEY_LENGTH];
+        public[..32].copy_from_slice(&self.curve25519_public);
+        public[32..].copy_from_slice(self.ed25519.public.as_bytes());
+        public
+    }
+}
+
+impl ZeroTierSigner for WorldKeypair {
+    fn sign(&self, data: &[u8]) -> Fallible<[u8; 96]> {
+        // Same signature format as the controller uses, the ed25519
+        // signature of the first 32 bytes of the SHA-512 hash followed by
+        // the hash.
+        let mut signature = [0u8; SIGNATURE_LENGTH];
+        let digest = &sha2::Sha512::digest(data)[..32];
+        signature[..64].copy_from_slice(&self.ed25519.sign(digest).to_bytes());
+        signature[64..].copy_from_slice(digest);
+        Ok(signature)
+    }
+}
+
+/// Builds a signed world definition
+///
+/// ```ignore
+/// let keypair = WorldKeypair::generate();
+/// let planet = WorldBuilder::new(WorldType::Planet, 0x1234)
+///     .root(Root::new(identity, vec!["192.0.2.1:9993".parse()?])?)
+///     .build(&keypair)?;
+/// std::fs::write("planet", planet.to_bytes())?;
+/// ```
+pub struct WorldBuilder {
+    world_type: WorldType,
+    id: u64,
+    timestamp: u64,
+    roots: Vec<Root>,
+}
+
+impl WorldBuilder {
+    pub fn new(world_type: WorldType, id: u64) -> Self {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).expect("unable to get time in millis");
+        Self {
+            world_type: world_type,
+            id: id,
+            timestamp: now.as_millis() as u64,
+            roots: Vec::new(),
+        }
+    }
+
+    /// Sets the timestamp (milliseconds since epoch), nodes only replace a
+    /// world with one that has a newer timestamp. Defaults to now.
+    pub fn timestamp(mut self, timestamp: u64) -> Self {
+        self.timestamp = timestamp;
+        self
+    }
+
+    pub fn root(mut self, root: Root) -> Self {
+        self.roots.push(root);
+        self
+    }
+
+    /// Signs the world, future updates have to be signed with the same keypair
+    pub fn build(self, keypair: &WorldKeypair) -> Fallible<World> {
+        if self.roots.is_empty() || self.roots.len() > MAX_ROOTS {
+            return Err(WorldError::RootCount(MAX_ROOTS).into());
+        }
+        if self.roots.iter().any(|r| r.stable_endpoints.len() > MAX_STABLE_ENDPOINTS) {
+            return Err(WorldError::EndpointCount(MAX_STABLE_ENDPOINTS).into());
+        }
+
+        let mut world = World {
+            world_type: self.world_type,
+            id: self.id,
+            timestamp: self.timestamp,
+            update_key: keypair.public(),
+            signature: [0u8; SIGNATURE_LENGTH],
+            roots: self.roots,
+        };
+        world.signature = keypair.sign(&world.serialize(true))?;
+        Ok(world)
+    }
+}
+
+/// Planet or moon definition in ZeroTier's World format
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct World {
+    pub world_type: WorldType,
+    pub id: u64,
+    /// Milliseconds since epoch
+    pub timestamp: u64,
+    /// Public key updates of the world have to be signed with
+    pub update_key: [u8; PUBLIC_KEY_LENGTH],
+    pub signature: [u8; SIGNATURE_LENGTH],
+    pub roots: Vec<Root>,
+}
+
+impl World {
+    /// Returns the world as stored in a planet or moon file
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.serialize(false)
+    }
+
+    pub fn from_bytes(buf: &[u8]) -> Fallible<Self> {
+        let mut r = Reader { buf: buf };
+        let world_type = match r.u8()? {
+            1 => WorldType::Planet,
+            127 => WorldType::Moon,
+            t => return Err(WorldError::UnknownType(t).into()),
+        };
+        let id = r.u64()?;
+        let timestamp = r.u64()?;
+        let update_key = r.take(PUBLIC_KEY_LENGTH)?.try_into().unwrap();
+        let signature = r.take(SIGNATURE_LENGTH)?.try_into().unwrap();
+
+        let root_count = r.u8()? as usize;
+        if root_count > MAX_ROOTS {
+            return Err(WorldError::RootCount(MAX_ROOTS).into());
+        }
+        let mut roots = Vec::with_capacity(root_count);
+        for _ in 0..root_count {
+            let address = r.take(5)?;
+            let mut addr_buf = [0u8; 8];
+            addr_buf[3..].copy_from_slice(address);
+            if r.u8()? != 0 {
+                return Err(WorldError::InvalidIdentity(hex::encode(address)).into());
+            }
+            let public = r.take(PUBLIC_KEY_LENGTH)?.try_into().unwrap();
+            // Secret keys are never part of a world, but skip them if they are
+            let secret_length = r.u8()? as usize;
+            r.take(secret_length)?;
+
+            let endpoint_count = r.u8()? as usize;
+            if endpoint_count > MAX_STABLE_ENDPOINTS {
+                return Err(WorldError::EndpointCount(MAX_STABLE_ENDPOINTS).into());
+            }
+            let mut stable_endpoints = Vec::with_capacity(endpoint_count);
+            for _ in 0..endpoint_count {
+                if let Some(endpoint) = r.endpoint()? {
+                    stable_endpoints.push(endpoint);
+                }
+            }
+
+            roots.push(Root {
+                address: u64::from_be_bytes(addr_buf),
+                public: public,
+                stable_endpoints: stable_endpoints,
+            });
+        }
+
+        Ok(Self {
+            world_type: world_type,
+            id: id,
+            timestamp: timestamp,
+            update_key: update_key,
+            signature: signature,
+            roots: roots,
+        })
+    }
+
+    /// Returns true if the world is signed with the given update key
+    ///
+    /// A new world is signed with its own update key, an update with the key
+    /// of the world it replaces.
+    pub fn verify(&self, update_key: &[u8; PUBLIC_KEY_LENGTH]) -> bool {
+        let public = match PublicKey::from_bytes(&update_key[32..]) {
+            Ok(public) => public,
+            Err(_) => return false,
+        };
+        let signature = match Signature::from_bytes(&self.signature[..64]) {
+            Ok(signature) => signature,
+            Err(_) => return false,
+        };
+        let digest = &sha2::Sha512::digest(&self.serialize(true))[..32];
+        digest == &self.signature[64..] && public.verify(digest, &signature).is_ok()
+    }
+
+    fn serialize(&self, for_sign: bool) -> Vec<u8> {
+        let mut buf = Vec::new();
+        if for_sign {
+            buf.extend_from_slice(&SIGN_PREFIX.to_be_bytes());
+        }
+        buf.push(self.world_type as u8);
+        buf.extend_from_slice(&self.id.to_be_bytes());
+        buf.extend_from_slice(&self.timestamp.to_be_bytes());
+        buf.extend_from_slice(&self.update_key);
+        if !for_sign {
+            buf.extend_from_slice(&self.signature);
+        }
+        buf.push(self.roots.len() as u8);
+        for root in &self.roots {
+            // Public identity: address, type (0 is C25519) and public key
+            // followed by the length of the secret key which is never included
+            buf.extend_from_slice(&root.address.to_be_bytes()[3..]);
+            buf.push(0);
+            buf.extend_from_slice(&root.public);
+            buf.push(0);
+
+            buf.push(root.stable_endpoints.len() as u8);
+            for endpoint in &root.stable_endpoints {
+                match endpoint.ip() {
+                    IpAddr::V4(ip) => {
+                        buf.push(0x04);
+                        buf.extend_from_slice(&ip.octets());
+                    },
+                    IpAddr::V6(ip) => {
+                        buf.push(0x06);
+                        buf.extend_from_slice(&ip.octets());
+                    },
+                }
+                buf.extend_from_slice(&endpoint.port().to_be_bytes());
+            }
+        }
+        if self.world_type == WorldType::Moon {
+            // Length of the attached dictionary, reserved for future use
+            buf.extend_from_slice(&0u16.to_be_bytes());
+        }
+        if for_sign {
+            buf.extend_from_slice(&SIGN_SUFFIX.to_be_bytes());
+        }
+        buf
+    }
+}
+
+// Reads big endian fields from a buffer
+struct Reader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Fallible<&'a [u8]> {
+        if self.buf.len() < len {
+            return Err(WorldError::Truncated.into());
+        }
+        let (data, rest) = self.buf.split_at(len);
+        self.buf = rest;
+        Ok(data)
+    }
+
+    fn u8(&mut self) -> Fallible<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Fallible<u16> {
+        Ok(u16::from_be_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Fallible<u64> {
+        Ok(u64::from_be_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    // Reads an endpoint, returns None for a nil address and the address types
+    // ZeroTier skips, like InetAddress::deserialize
+    fn endpoint(&mut self) -> Fallible<Option<SocketAddr>> {
+        let ip: IpAddr = match self.u8()? {
+            0x00 => return Ok(None),
+            // Ethernet and Bluetooth addresses
+            0x01 | 0x02 => {
+                self.take(6)?;
+                return Ok(None);
+            },
+            // Other addresses have their length first
+            0x03 => {
+                let len = self.u16()? as usize;
+                self.take(len)?;
+                return Ok(None);
+            },
+            0x04 => <[u8; 4]>::try_from(self.take(4)?).unwrap().into(),
+            0x06 => <[u8; 16]>::try_from(self.take(16)?).unwrap().into(),
+            t => return Err(WorldError::UnknownAddressType(t).into()),
+        };
+        Ok(Some(SocketAddr::new(ip, self.u16()?)))
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    const IDENTITY: &str = "894f8955a6:0:2ca7d749ec20a750b6189cf1f51a5f7db67bbed6218cbae506946c01e267cd05d6e4bd580af21231b7edd03eb04a086a43a14cfca67b19a1cc4484e5ad142034";
+
+    #[test]
+    fn test_build_planet() -> Fallible<()> {
+        let keypair = WorldKeypair::generate();
+        let root = Root::new(IDENTITY, vec![
+            SocketAddr::from_str("192.0.2.1:9993")?,
+            SocketAddr::from_str("[2001:db8::1]:9993")?,
+        ])?;
+        assert_eq!(root.address, 0x894f8955a6);
+
+        let planet = WorldBuilder::new(WorldType::Planet, 0x2a)
+            .timestamp(1650367222104)
+            .root(root)
+            .build(&keypair)?;
+        assert!(planet.verify(&keypair.public()));
+        assert!(!planet.verify(&WorldKeypair::generate().public()));
+
+        let buf = planet.to_bytes();
+        // Header, signature, one root with an IPv4 and an IPv6 endpoint
+        assert_eq!(buf.len(), 1 + 8 + 8 + 64 + 96 + 1 + (5 + 1 + 64 + 1) + 1 + 7 + 19);
+        assert_eq!(buf[0], 1);
+        assert_eq!(u64::from_be_bytes(buf[1..9].try_into().unwrap()), 0x2a);
+        assert_eq!(&buf[178..183], &[0x89, 0x4f, 0x89, 0x55, 0xa6]);
+
+        assert_eq!(World::from_bytes(&buf)?, planet);
+        assert!(World::from_bytes(&buf[..buf.len() - 1]).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_skipped_endpoints() -> Fallible<()> {
+        let mut buf = vec![0x00, 0x01];
+        buf.extend_from_slice(&[0xaa; 6]);
+        buf.push(0x02);
+        buf.extend_from_slice(&[0xbb; 6]);
+        buf.extend_from_slice(&[0x03, 0x00, 0x03, 0xcc, 0xcc, 0xcc]);
+        buf.extend_from_slice(&[0x04, 192, 0, 2, 1, 0x27, 0x09]);
+        let mut r = Reader { buf: &buf };
+
+        for _ in 0..4 {
+            assert_eq!(r.endpoint()?, None);
+        }
+        assert_eq!(r.endpoint()?, Some("192.0.2.1:9993".parse()?));
+        assert!(r.buf.is_empty());
+        assert!(Reader { buf: &[0x05] }.endpoint().is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_keypair_bytes() -> Fallible<()> {
+        let keypair = WorldKeypair::generate();
+        let restored = WorldKeypair::from_bytes(&keypair.to_bytes())?;
+        assert_eq!(restored.to_bytes()[..], keypair.to_bytes()[..]);
+
+        let moon = WorldBuilder::new(WorldType::Moon, 0x894f8955a6)
+            .root(Root::new(IDENTITY, vec![])?)
+            .build(&restored)?;
+        assert!(moon.verify(&keypair.public()));
+        assert_eq!(World::from_bytes(&moon.to_bytes())?, moon);
+
+        assert!(WorldBuilder::new(WorldType::Planet, 1).build(&keypair).is_err());
+
+        Ok(())
+    }
+}
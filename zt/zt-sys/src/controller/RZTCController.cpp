@@ -11,6 +11,7 @@
 #include <NetworkController.hpp>
 #include <Capability.hpp>
 #include <CertificateOfOwnership.hpp>
+#include <Revocation.hpp>
 #include <Tag.hpp>
 
 namespace ZeroTier {
@@ -88,6 +89,18 @@ void RZTCController::sendError(
 	_sender->ncSendError(nwid, requestPacketId, destAddr, errorCode, errorData, errorDataSize);
 }
 
+void RZTCController::sendRevocation(
+	uint64_t nwid,
+	uint32_t revocationId,
+	uint64_t threshold,
+	const Address &target,
+	const Address &destAddr)
+{
+	Revocation rev(revocationId, nwid, 0, threshold, ZT_REVOCATION_FLAG_FAST_PROPAGATE, target, Revocation::CREDENTIAL_TYPE_COM);
+	if (rev.sign(_signingId))
+		_sender->ncSendRevocation(destAddr, rev);
+}
+
 } // namespace ZeroTier
 
 extern "C" {
@@ -154,4 +167,24 @@ void RZTC_Controller_sendError(
 	} catch ( ... ) {}
 }
 
+void RZTC_Controller_sendRevocation(
+	RZTC_Controller *controller,
+	uint64_t nwid,
+	uint32_t revocationId,
+	uint64_t threshold,
+	uint64_t target,
+	uint64_t dest)
+{
+	try {
+		std::unique_ptr<ZeroTier::Address> targetAddr(new ZeroTier::Address(target));
+		std::unique_ptr<ZeroTier::Address> destAddr(new ZeroTier::Address(dest));
+		reinterpret_cast<ZeroTier::RZTCController*>(controller)->sendRevocation(
+			nwid,
+			revocationId,
+			threshold,
+			*(targetAddr.get()),
+			*(destAddr.get()));
+	} catch ( ... ) {}
+}
+
 } // extern "C"
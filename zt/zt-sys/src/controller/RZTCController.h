@@ -76,6 +76,8 @@ void RZTC_Controller_sendConfig(RZTC_Controller *controller,uint64_t nwid,uint64
 
 void RZTC_Controller_sendError(RZTC_Controller *controller,uint64_t nwid,uint64_t requestPacketId,uint64_t dest,enum RZTC_NetworkErrorCode errorCode,const void* errorData, unsigned int errorDataSize);
 
+void RZTC_Controller_sendRevocation(RZTC_Controller *controller,uint64_t nwid,uint32_t revocationId,uint64_t threshold,uint64_t target,uint64_t dest);
+
 #ifdef __cplusplus
 } // extern "C"
 #endif
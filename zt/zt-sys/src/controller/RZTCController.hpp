@@ -61,6 +61,25 @@ public:
 		const void *errorData,
 		unsigned int errorDataSize);
 
+	/**
+	 * Revoke the certificate of membership of a member
+	 *
+	 * The revocation is signed with the controller's identity and sent to destAddr,
+	 * which should be every other member of the network.
+	 *
+	 * @param nwid 64-bit network ID
+	 * @param revocationId Random ID of the revocation
+	 * @param threshold Certificates of membership with timestamps up to this are revoked
+	 * @param target Member whose certificate of membership is revoked
+	 * @param destAddr Member to send the revocation to
+	 */
+	virtual void sendRevocation(
+		uint64_t nwid,
+		uint32_t revocationId,
+		uint64_t threshold,
+		const Address &target,
+		const Address &destAddr);
+
 private:
 	Identity _signingId;
 	NetworkController::Sender *_sender;
@@ -112,3 +112,13 @@ extern "C" {
         errorDataSize: ::std::os::raw::c_uint,
     );
 }
+extern "C" {
+    pub fn RZTC_Controller_sendRevocation(
+        controller: *mut RZTC_Controller,
+        nwid: u64,
+        revocationId: u32,
+        threshold: u64,
+        target: u64,
+        dest: u64,
+    );
+}